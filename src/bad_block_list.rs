@@ -0,0 +1,85 @@
+//! Listing the bad blocks of a device the way `badblocks -o` does, so
+//! that the list can be fed to `e2fsck -l` or `mke2fs -l`.
+
+use std::{
+    collections::BTreeSet,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+
+use crate::file_name;
+
+/// The numbers of the blocks of a device that hold a bad sector, in
+/// units of a block size of choice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BadBlockList {
+    block_size: u64,
+    blocks: BTreeSet<u64>,
+}
+
+impl BadBlockList {
+    pub(crate) fn new(block_size: u64) -> Self {
+        Self {
+            block_size,
+            blocks: BTreeSet::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Add the blocks that the `len` bad bytes at `offset` are in.
+    pub(crate) fn add(&mut self, offset: u64, len: u64) {
+        let last = (offset + len.max(1) - 1) / self.block_size;
+        self.blocks.extend(offset / self.block_size..=last);
+    }
+
+    /// Write the list into `dir`, into a file named after the device
+    /// named `device` (its serial number, or the name of its path).
+    pub(crate) fn save(&self, dir: &Path, device: &str) -> anyhow::Result<PathBuf> {
+        let path = dir.join(format!("{}.badblocks", file_name::for_device(device)));
+        fs::write(&path, self.to_string())
+            .with_context(|| format!("Writing the bad block list {path:?}"))?;
+        Ok(path)
+    }
+}
+
+impl fmt::Display for BadBlockList {
+    /// One block number per line, in order.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for block in &self.blocks {
+            writeln!(f, "{block}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(512, "9\n10\n16\n"; "sectors")]
+    #[test_case(4096, "1\n2\n"; "filesystem blocks")]
+    #[test_case(256, "18\n19\n20\n21\n32\n33\n"; "smaller than a sector")]
+    fn lists_the_blocks_holding_bad_sectors(block_size: u64, expected: &str) {
+        let mut list = BadBlockList::new(block_size);
+        for sector in [9, 10, 16] {
+            list.add(sector * 512, 512);
+        }
+        assert_eq!(list.to_string(), expected);
+    }
+
+    #[test]
+    fn saves_the_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut list = BadBlockList::new(4096);
+        list.add(8192, 512);
+        let path = list.save(dir.path(), "WD-WCC/4E1").unwrap();
+        assert_eq!(path, dir.path().join("WD-WCC_4E1.badblocks"));
+        assert_eq!(fs::read_to_string(path).unwrap(), "2\n");
+    }
+}
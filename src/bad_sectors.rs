@@ -0,0 +1,238 @@
+//! Pinning read and write errors down to the sectors that cause them,
+//! so that a test can carry on past them.
+
+use std::{collections::BTreeSet, fmt, io, ops::Range};
+
+use crate::engine::{Engine, IoBuf};
+
+/// Number of adjacent buffers that may fail entirely before the device
+/// counts as lost, rather than as having bad sectors.
+const MAX_LOST_BUFFERS: usize = 8;
+
+/// The sectors of a device that could not be read or written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BadSectors {
+    /// The device's logical sector size, the smallest unit it can
+    /// transfer.
+    pub sector_size: usize,
+    /// Byte offsets of the sectors that failed.
+    offsets: BTreeSet<u64>,
+    /// The end of the last buffer that failed entirely, and how many
+    /// adjacent buffers before it did too.
+    lost: Option<(u64, usize)>,
+}
+
+impl BadSectors {
+    pub(crate) fn new(sector_size: usize) -> Self {
+        Self {
+            sector_size,
+            offsets: BTreeSet::new(),
+            lost: None,
+        }
+    }
+
+    /// The sectors with the numbers `sectors` failed already, e.g. in
+    /// the part of a test that was checkpointed.
+    pub(crate) fn from_sectors(sector_size: usize, sectors: impl IntoIterator<Item = u64>) -> Self {
+        Self {
+            offsets: sectors
+                .into_iter()
+                .map(|sector| sector * sector_size as u64)
+                .collect(),
+            ..Self::new(sector_size)
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Numbers of the sectors that failed, in order.
+    pub(crate) fn sectors(&self) -> impl Iterator<Item = u64> + '_ {
+        self.offsets
+            .iter()
+            .map(|offset| offset / self.sector_size as u64)
+    }
+
+    /// Find the sectors that make transferring `buf` at `offset` fail
+    /// with `error`, by transferring ever smaller parts of it, and
+    /// return their byte offsets within `buf`. The parts that can be
+    /// transferred are, so a buffer that is read this way holds what
+    /// the device has in the sectors that did not fail.
+    ///
+    /// Fails instead if the device is gone, or if so many adjacent
+    /// buffers failed entirely that it seems to be. The sectors found
+    /// to fail until then are recorded all the same.
+    pub(crate) fn bisect<B: IoBuf>(
+        &mut self,
+        engine: &mut dyn Engine<B>,
+        offset: u64,
+        buf: &mut B,
+        error: &io::Error,
+    ) -> anyhow::Result<Vec<usize>> {
+        gone(error)?;
+        let mut failed = vec![];
+        // The whole buffer failed already, so start with its halves:
+        self.split(engine, offset, buf, 0..buf.len(), &mut failed)?;
+        if failed.len() * self.sector_size < buf.len() {
+            self.lost = None;
+            return Ok(failed);
+        }
+        let in_a_row = match self.lost {
+            Some((end, in_a_row)) if end == offset => in_a_row + 1,
+            _ => 1,
+        };
+        if in_a_row >= MAX_LOST_BUFFERS {
+            anyhow::bail!(
+                "The device seems to be lost: {in_a_row} buffers in a row failed entirely, up to offset={}",
+                offset + buf.len() as u64
+            );
+        }
+        self.lost = Some((offset + buf.len() as u64, in_a_row));
+        Ok(failed)
+    }
+
+    /// Adds the offsets of the sectors in `part` that fail to `failed`.
+    fn split<B: IoBuf>(
+        &mut self,
+        engine: &mut dyn Engine<B>,
+        offset: u64,
+        buf: &mut B,
+        part: Range<usize>,
+        failed: &mut Vec<usize>,
+    ) -> anyhow::Result<()> {
+        let sectors = part.len() / self.sector_size;
+        if sectors <= 1 {
+            self.offsets.insert(offset + part.start as u64);
+            failed.push(part.start);
+            return Ok(());
+        }
+        let middle = part.start + sectors / 2 * self.sector_size;
+        for half in [part.start..middle, middle..part.end] {
+            if let Err(e) = engine.transfer_part(offset, buf, half.clone()) {
+                gone(&e)?;
+                self.split(engine, offset, buf, half, failed)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails if `error` means that the device is gone, so that there is no
+/// point in looking for the sectors that fail.
+fn gone(error: &io::Error) -> anyhow::Result<()> {
+    match error.raw_os_error() {
+        Some(libc::ENODEV | libc::ENXIO) => anyhow::bail!("The device is gone: {error}"),
+        _ => Ok(()),
+    }
+}
+
+impl fmt::Display for BadSectors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, sector) in self.sectors().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{sector}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{engine::Done, IOBuffer};
+    use std::io;
+
+    /// Fails to transfer anything that touches a sector for which it
+    /// returns an error number.
+    struct FailingSectors(fn(u64) -> Option<i32>);
+
+    impl Engine<IOBuffer> for FailingSectors {
+        fn has_room(&self) -> bool {
+            unreachable!()
+        }
+
+        fn submit(&mut self, _: u64, _: IOBuffer) -> io::Result<()> {
+            unreachable!()
+        }
+
+        fn complete(&mut self) -> io::Result<Option<Done<IOBuffer>>> {
+            unreachable!()
+        }
+
+        fn transfer_part(
+            &mut self,
+            offset: u64,
+            buf: &mut IOBuffer,
+            part: Range<usize>,
+        ) -> io::Result<()> {
+            let (start, end) = (offset + part.start as u64, offset + part.end as u64);
+            if let Some(errno) = (start / 512..end.div_ceil(512)).find_map(self.0) {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            buf[part].fill(1);
+            Ok(())
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            unreachable!()
+        }
+    }
+
+    fn buffer() -> IOBuffer {
+        let mut buf = IOBuffer::with_capacity(8192);
+        buf.resize(8192, 0);
+        buf
+    }
+
+    fn eio() -> io::Error {
+        io::Error::from_raw_os_error(libc::EIO)
+    }
+
+    #[test]
+    fn finds_the_failing_sectors() {
+        let mut buf = buffer();
+        let mut bad = BadSectors::new(512);
+        let mut engine =
+            FailingSectors(|sector| [17, 18, 30].contains(&sector).then_some(libc::EIO));
+        let found = bad.bisect(&mut engine, 8192, &mut buf, &eio()).unwrap();
+        assert_eq!(found, [512, 1024, 7168]);
+        assert_eq!(bad.to_string(), "17,18,30");
+        // Everything else was transferred:
+        let transferred = buf.chunks(512).filter(|sector| sector[0] == 1).count();
+        assert_eq!(transferred, 13);
+    }
+
+    #[test]
+    fn gives_up_on_a_lost_device() {
+        let mut buf = buffer();
+        let mut bad = BadSectors::new(512);
+        let mut engine = FailingSectors(|_| Some(libc::EIO));
+        for i in 0..MAX_LOST_BUFFERS as u64 - 1 {
+            bad.bisect(&mut engine, i * 8192, &mut buf, &eio()).unwrap();
+        }
+        let offset = (MAX_LOST_BUFFERS as u64 - 1) * 8192;
+        assert!(bad.bisect(&mut engine, offset, &mut buf, &eio()).is_err());
+        // Including those of the last buffer:
+        assert_eq!(bad.len(), MAX_LOST_BUFFERS * 16);
+    }
+
+    #[test]
+    fn gives_up_on_a_device_that_is_gone() {
+        let mut buf = buffer();
+        let mut bad = BadSectors::new(512);
+        let mut engine = FailingSectors(|sector| match sector {
+            3 => Some(libc::EIO),
+            _ => Some(libc::ENODEV),
+        });
+        assert!(bad.bisect(&mut engine, 0, &mut buf, &eio()).is_err());
+        let gone = io::Error::from_raw_os_error(libc::ENXIO);
+        assert!(bad.bisect(&mut engine, 0, &mut buf, &gone).is_err());
+    }
+}
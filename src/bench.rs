@@ -0,0 +1,171 @@
+//! Measuring how fast the garbage generators are on this machine.
+
+use std::{
+    io::Read as _,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use indicatif::HumanBytes;
+use tracing::{debug, info, warn};
+
+use crate::garbage::GarbageGeneratorVariant;
+
+/// Number of bytes each thread generates when picking a generator for
+/// `--generator=auto`; enough to get past startup costs, but short
+/// enough not to delay the test noticeably.
+const AUTO_BYTES_PER_THREAD: u64 = 64 * 1024 * 1024;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct BenchArgs {
+    /// Number of bytes each generator produces per read.
+    #[clap(long, default_value_t = NonZeroUsize::new(8192).unwrap())]
+    buffer_size: NonZeroUsize,
+
+    /// Number of threads for the multi-threaded measurement. Defaults to the number of CPU cores.
+    #[clap(long)]
+    threads: Option<NonZeroUsize>,
+
+    /// Number of bytes to generate on each thread.
+    #[clap(long, default_value_t = 1024 * 1024 * 1024)]
+    bytes: u64,
+}
+
+/// Time every generator on one and on N threads, and log the results.
+pub(crate) fn run(args: &BenchArgs) -> anyhow::Result<()> {
+    let threads = args
+        .threads
+        .map_or_else(rayon::current_num_threads, NonZeroUsize::get);
+    for variant in GarbageGeneratorVariant::benchmarkable() {
+        for threads in [1, threads] {
+            let throughput = measure(&variant, args.buffer_size.get(), threads, args.bytes)
+                .with_context(|| format!("Benchmarking the {variant} generator"))?;
+            info!(
+                generator = %variant,
+                threads,
+                buffer_size = args.buffer_size.get(),
+                throughput = %format_throughput(throughput),
+                per_thread = %format_throughput(throughput / threads as f64),
+                "Benchmark result"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Pick the generator with the highest throughput when running on one
+/// thread per device, of those that work on this machine (e.g. the
+/// `shishua` CLI tool may not be installed).
+pub(crate) fn fastest(
+    buffer_size: usize,
+    devices: usize,
+) -> anyhow::Result<GarbageGeneratorVariant> {
+    fastest_of(
+        GarbageGeneratorVariant::benchmarkable(),
+        buffer_size,
+        devices,
+        AUTO_BYTES_PER_THREAD,
+    )
+}
+
+/// Pick the fastest of `variants`, each generating `bytes` bytes on
+/// each of `threads` threads.
+fn fastest_of(
+    variants: Vec<GarbageGeneratorVariant>,
+    buffer_size: usize,
+    threads: usize,
+    bytes: u64,
+) -> anyhow::Result<GarbageGeneratorVariant> {
+    let mut best = None;
+    for variant in variants {
+        let throughput = match measure(&variant, buffer_size, threads, bytes) {
+            Ok(throughput) => throughput,
+            Err(e) => {
+                warn!(generator = %variant, error = %format!("{e:#}"), "Could not benchmark the generator; not choosing it");
+                continue;
+            }
+        };
+        debug!(generator = %variant, throughput = %format_throughput(throughput), "Measured");
+        if best.as_ref().is_none_or(|(_, best)| throughput > *best) {
+            best = Some((variant, throughput));
+        }
+    }
+    let (variant, throughput) = best.context("None of the generators could be benchmarked")?;
+    info!(generator = %variant, devices = threads, throughput = %format_throughput(throughput), "Automatically selected the fastest generator");
+    Ok(variant)
+}
+
+/// Run `threads` generators in parallel, each producing `bytes` bytes,
+/// and return the combined throughput in bytes per second.
+fn measure(
+    variant: &GarbageGeneratorVariant,
+    buffer_size: usize,
+    threads: usize,
+    bytes: u64,
+) -> anyhow::Result<f64> {
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        let workers: Vec<_> = (0..threads)
+            .map(|seed| s.spawn(move || generate(variant, buffer_size, seed as u64, bytes)))
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("Benchmark thread panicked"))
+            .collect::<anyhow::Result<Vec<()>>>()
+    })?;
+    let elapsed = start.elapsed().max(Duration::from_nanos(1));
+    Ok((bytes * threads as u64) as f64 / elapsed.as_secs_f64())
+}
+
+fn generate(
+    variant: &GarbageGeneratorVariant,
+    buffer_size: usize,
+    seed: u64,
+    bytes: u64,
+) -> anyhow::Result<()> {
+    let mut generator = variant.to_generator(seed.into())?;
+    let mut buf = vec![0; buffer_size];
+    let mut done = 0;
+    while done < bytes {
+        generator
+            .read_exact(&mut buf)
+            .context("Generating pseudorandom data")?;
+        done += buffer_size as u64;
+    }
+    Ok(())
+}
+
+fn format_throughput(bytes_per_sec: f64) -> String {
+    format!("{}/s", HumanBytes(bytes_per_sec as u64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn measures_throughput() {
+        let throughput = measure(&GarbageGeneratorVariant::Aes, 4096, 2, 1 << 20).unwrap();
+        assert!(throughput > 0.0);
+    }
+
+    #[test]
+    fn skips_generators_that_fail() {
+        let missing: GarbageGeneratorVariant = "cmd:disk-spinner-no-such-program".parse().unwrap();
+        let fastest = fastest_of(
+            vec![missing, GarbageGeneratorVariant::Pattern(vec![0xaa])],
+            4096,
+            1,
+            1 << 20,
+        )
+        .unwrap();
+        assert_eq!(fastest, GarbageGeneratorVariant::Pattern(vec![0xaa]));
+    }
+
+    #[test]
+    fn fails_without_working_generators() {
+        let missing: GarbageGeneratorVariant = "cmd:disk-spinner-no-such-program".parse().unwrap();
+        assert!(fastest_of(vec![missing], 4096, 1, 1 << 20).is_err());
+    }
+}
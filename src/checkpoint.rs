@@ -0,0 +1,442 @@
+//! Checkpoints, from which an interrupted test can resume.
+//!
+//! Each device's test regularly records how far it got in a small
+//! text file, named after the device's serial number, so that the
+//! checkpoint still applies when the device shows up under a different
+//! path after a reboot.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use tracing::{debug, info};
+
+use crate::{
+    bad_sectors::BadSectors,
+    file_name,
+    garbage::{token::RunToken, Seed},
+};
+
+/// How often a device's checkpoint is saved while its test runs.
+const INTERVAL: Duration = Duration::from_secs(30);
+
+/// The part of a pass that a test is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Write,
+    Verify,
+    /// All passes are finished.
+    Done,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Write => "write",
+            Phase::Verify => "verify",
+            Phase::Done => "done",
+        })
+    }
+}
+
+impl FromStr for Phase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "write" => Ok(Phase::Write),
+            "verify" => Ok(Phase::Verify),
+            "done" => Ok(Phase::Done),
+            _ => anyhow::bail!("Unknown phase {s:?}"),
+        }
+    }
+}
+
+/// How far the test of a device got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Checkpoint {
+    pub serial: String,
+    pub run_id: u64,
+    /// The seed that the run was started with.
+    pub seed: Seed,
+    pub buffer_size: usize,
+    /// The bytes of the device that the run tests.
+    pub start: u64,
+    pub end: u64,
+    pub pass: usize,
+    /// The generator and (per-device) seed of the pass.
+    pub token: RunToken,
+    pub phase: Phase,
+    /// Everything before this offset has been written or verified in
+    /// this phase.
+    pub offset: u64,
+    /// Number of bad blocks found so far, in all passes.
+    pub bad_blocks: usize,
+    /// Numbers of the sectors that could not be read or written so
+    /// far, in all passes.
+    pub bad_sectors: Vec<u64>,
+}
+
+impl Checkpoint {
+    /// The file with `extension` that the checkpoint of the device
+    /// with `serial` is kept in.
+    fn path(dir: &Path, serial: &str, extension: &str) -> PathBuf {
+        dir.join(format!("{}.{extension}", file_name::for_device(serial)))
+    }
+
+    /// Load the checkpoint of the device with `serial`, if there is one.
+    pub(crate) fn load(dir: &Path, serial: &str) -> anyhow::Result<Option<Self>> {
+        let path = Self::path(dir, serial, "checkpoint");
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Reading checkpoint {path:?}")),
+        };
+        let checkpoint: Self = contents
+            .parse()
+            .with_context(|| format!("Parsing checkpoint {path:?}"))?;
+        if checkpoint.serial != serial {
+            anyhow::bail!(
+                "Checkpoint {path:?} is for the device with serial {:?}, not {serial:?}",
+                checkpoint.serial
+            );
+        }
+        Ok(Some(checkpoint))
+    }
+
+    /// Save the checkpoint, replacing the previous one at once so that
+    /// a crash can't leave a partial checkpoint behind.
+    fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        self.write(dir, &Self::path(dir, &self.serial, "checkpoint"))
+    }
+
+    /// Save the checkpoint into `dir` as the partial result of an
+    /// interrupted test that has no checkpoint directory, in a file
+    /// named after `device` (its serial number, or the name of its
+    /// path) with the extension "result".
+    pub(crate) fn save_result(&self, dir: &Path, device: &str) -> anyhow::Result<PathBuf> {
+        let path = Self::path(dir, device, "result");
+        self.write(dir, &path)?;
+        Ok(path)
+    }
+
+    fn write(&self, dir: &Path, path: &Path) -> anyhow::Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        // Checkpoints must survive a power loss, so each step must
+        // reach the disk before the next:
+        let save = || -> io::Result<()> {
+            let mut file = File::create(&partial)?;
+            file.write_all(self.to_string().as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&partial, path)?;
+            File::open(dir)?.sync_all()
+        };
+        save().with_context(|| format!("Saving checkpoint {path:?}"))
+    }
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "serial={}", self.serial)?;
+        writeln!(f, "run_id={}", self.run_id)?;
+        writeln!(f, "seed={}", self.seed)?;
+        writeln!(f, "buffer_size={}", self.buffer_size)?;
+        writeln!(f, "start={}", self.start)?;
+        writeln!(f, "end={}", self.end)?;
+        writeln!(f, "pass={}", self.pass)?;
+        writeln!(f, "token={}", self.token)?;
+        writeln!(f, "phase={}", self.phase)?;
+        writeln!(f, "offset={}", self.offset)?;
+        writeln!(f, "bad_blocks={}", self.bad_blocks)?;
+        write!(f, "bad_sectors=")?;
+        for (i, sector) in self.bad_sectors.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{sector}")?;
+        }
+        writeln!(f)
+    }
+}
+
+impl FromStr for Checkpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = BTreeMap::new();
+        for line in s.lines().filter(|line| !line.trim().is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("Invalid line {line:?}; expected key=value"))?;
+            fields.insert(key.trim(), value.trim());
+        }
+        fn field<T>(fields: &BTreeMap<&str, &str>, key: &str) -> anyhow::Result<T>
+        where
+            T: FromStr,
+            T::Err: Into<anyhow::Error>,
+        {
+            let value = fields
+                .get(key)
+                .with_context(|| format!("Missing {key:?}"))?;
+            value
+                .parse()
+                .map_err(Into::into)
+                .with_context(|| format!("Invalid {key:?}: {value:?}"))
+        }
+        Ok(Self {
+            serial: field(&fields, "serial")?,
+            run_id: field(&fields, "run_id")?,
+            seed: field(&fields, "seed")?,
+            buffer_size: field(&fields, "buffer_size")?,
+            start: field(&fields, "start")?,
+            end: field(&fields, "end")?,
+            pass: field(&fields, "pass")?,
+            token: field(&fields, "token")?,
+            phase: field(&fields, "phase")?,
+            offset: field(&fields, "offset")?,
+            bad_blocks: field(&fields, "bad_blocks")?,
+            bad_sectors: field::<String>(&fields, "bad_sectors")?
+                .split(',')
+                .filter(|sector| !sector.is_empty())
+                .map(|sector| {
+                    sector
+                        .parse()
+                        .with_context(|| format!("Invalid bad sector {sector:?}"))
+                })
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+/// Load the checkpoints of the devices with `serials` that have one,
+/// by serial.
+pub(crate) fn load_all<'a>(
+    dir: &Path,
+    serials: impl IntoIterator<Item = &'a str>,
+) -> anyhow::Result<BTreeMap<String, Checkpoint>> {
+    let mut checkpoints = BTreeMap::new();
+    for serial in serials {
+        match Checkpoint::load(dir, serial)? {
+            Some(checkpoint) => {
+                checkpoints.insert(serial.to_owned(), checkpoint);
+            }
+            None => info!(serial, "No checkpoint; the device is tested from the start"),
+        }
+    }
+    Ok(checkpoints)
+}
+
+/// Keeps track of how far the test of a device got, and keeps its
+/// checkpoint up to date if there is a directory to save it in.
+pub(crate) struct Checkpointer {
+    dir: Option<PathBuf>,
+    checkpoint: Checkpoint,
+    /// Number of bad blocks found before the current phase.
+    bad_blocks_before: usize,
+    saved: Instant,
+}
+
+impl Checkpointer {
+    pub(crate) fn new(dir: Option<&Path>, checkpoint: Checkpoint) -> Self {
+        Self {
+            dir: dir.map(Path::to_owned),
+            bad_blocks_before: checkpoint.bad_blocks,
+            checkpoint,
+            saved: Instant::now(),
+        }
+    }
+
+    /// Record that a phase of a pass starts at `offset`, with
+    /// `bad_blocks` found before it.
+    pub(crate) fn start(
+        &mut self,
+        pass: usize,
+        token: &RunToken,
+        phase: Phase,
+        offset: u64,
+        bad_blocks: usize,
+    ) -> anyhow::Result<()> {
+        self.bad_blocks_before = bad_blocks;
+        self.checkpoint = Checkpoint {
+            pass,
+            token: token.clone(),
+            phase,
+            offset,
+            bad_blocks,
+            ..self.checkpoint.clone()
+        };
+        self.save()
+    }
+
+    /// Record that everything before `offset` is done in the current
+    /// phase, which found `bad_blocks` so far, and that `bad_sectors`
+    /// failed so far. Saved only every so often, after `flush` made
+    /// sure that what is recorded as done is on the device (e.g. not
+    /// just in its write cache).
+    pub(crate) fn progress(
+        &mut self,
+        offset: u64,
+        bad_blocks: usize,
+        bad_sectors: Option<&BadSectors>,
+        flush: impl FnOnce() -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.checkpoint.offset = offset;
+        self.checkpoint.bad_blocks = self.bad_blocks_before + bad_blocks;
+        // Sectors are only ever added, so there are new ones exactly
+        // when there are more:
+        if let Some(bad_sectors) = bad_sectors {
+            if bad_sectors.len() != self.checkpoint.bad_sectors.len() {
+                self.checkpoint.bad_sectors = bad_sectors.sectors().collect();
+            }
+        }
+        if self.dir.is_none() || self.saved.elapsed() < INTERVAL {
+            return Ok(());
+        }
+        flush()?;
+        self.save()
+    }
+
+    /// Record that all passes are finished, having found `bad_blocks`.
+    pub(crate) fn finish(&mut self, bad_blocks: usize) -> anyhow::Result<()> {
+        self.checkpoint.phase = Phase::Done;
+        self.checkpoint.bad_blocks = bad_blocks;
+        self.save()
+    }
+
+    /// Number of bad blocks found so far, in all passes.
+    pub(crate) fn bad_blocks(&self) -> usize {
+        self.checkpoint.bad_blocks
+    }
+
+    /// Save how far the test got right away, as it was interrupted.
+    pub(crate) fn interrupt(&mut self) -> anyhow::Result<Checkpoint> {
+        self.save()?;
+        Ok(self.checkpoint.clone())
+    }
+
+    fn save(&mut self) -> anyhow::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        self.checkpoint.save(dir)?;
+        self.saved = Instant::now();
+        debug!(checkpoint = ?self.checkpoint, "Saved checkpoint");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::garbage::GarbageGeneratorVariant;
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint {
+            serial: "WD-WCC4E1234567".to_string(),
+            run_id: 7,
+            seed: Seed::Short(42),
+            buffer_size: 4096,
+            start: 0,
+            end: 1 << 30,
+            pass: 1,
+            token: RunToken {
+                generator: "compressible:2.5".parse().unwrap(),
+                seed: Seed::Key128([3; 16]),
+            },
+            phase: Phase::Verify,
+            offset: 1 << 20,
+            bad_blocks: 2,
+            bad_sectors: vec![17, 18, 1 << 40],
+        }
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            Checkpoint::load(dir.path(), "WD-WCC4E1234567").unwrap(),
+            None
+        );
+        checkpoint().save(dir.path()).unwrap();
+        assert_eq!(
+            Checkpoint::load(dir.path(), "WD-WCC4E1234567").unwrap(),
+            Some(checkpoint())
+        );
+    }
+
+    #[test]
+    fn loads_checkpoints_without_bad_sectors() {
+        let checkpoint = Checkpoint {
+            bad_sectors: vec![],
+            ..checkpoint()
+        };
+        assert!(checkpoint.to_string().contains("bad_sectors=\n"));
+        assert_eq!(
+            checkpoint.to_string().parse::<Checkpoint>().unwrap(),
+            checkpoint
+        );
+    }
+
+    #[test]
+    fn keeps_the_bad_sectors() {
+        let mut checkpointer = Checkpointer::new(None, checkpoint());
+        let bad_sectors = BadSectors::from_sectors(512, [3, 17, 18, 1 << 40]);
+        checkpointer
+            .progress(8192, 0, Some(&bad_sectors), || Ok(()))
+            .unwrap();
+        assert_eq!(
+            checkpointer.interrupt().unwrap().bad_sectors,
+            [3, 17, 18, 1 << 40]
+        );
+    }
+
+    #[test]
+    fn names_files_after_the_serial() {
+        let path = Checkpoint::path(Path::new("/tmp"), "../ST4000 DM/1", "checkpoint");
+        assert_eq!(path, Path::new("/tmp/.._ST4000_DM_1.checkpoint"));
+    }
+
+    #[test]
+    fn saves_partial_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = checkpoint().save_result(dir.path(), "sdb").unwrap();
+        assert_eq!(saved, dir.path().join("sdb.result"));
+        let result: Checkpoint = fs::read_to_string(saved).unwrap().parse().unwrap();
+        assert_eq!(result, checkpoint());
+    }
+
+    #[test]
+    fn saves_progress_only_every_so_often() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut checkpointer = Checkpointer::new(Some(dir.path()), checkpoint());
+        let token = RunToken {
+            generator: GarbageGeneratorVariant::Aes,
+            seed: Seed::Short(42),
+        };
+        checkpointer.start(2, &token, Phase::Write, 0, 3).unwrap();
+        checkpointer
+            .progress(4096, 0, None, || panic!("Flushed without saving"))
+            .unwrap();
+        let saved = Checkpoint::load(dir.path(), "WD-WCC4E1234567")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (saved.pass, saved.phase, saved.offset),
+            (2, Phase::Write, 0)
+        );
+
+        checkpointer.finish(3).unwrap();
+        let saved = Checkpoint::load(dir.path(), "WD-WCC4E1234567")
+            .unwrap()
+            .unwrap();
+        assert_eq!((saved.phase, saved.bad_blocks), (Phase::Done, 3));
+    }
+}
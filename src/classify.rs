@@ -0,0 +1,249 @@
+//! Telling apart the ways in which a block can hold the wrong data.
+//!
+//! Blocks of zeroes or of 0xff point at the media or a drive that gave
+//! up on a sector, a few flipped bits at the media or the data path,
+//! and data that belongs somewhere else at firmware or cabling.
+
+use std::{collections::BTreeMap, fmt, io::Read as _};
+
+use anyhow::Context as _;
+
+use crate::{
+    garbage::{
+        stamp::{Diagnosis, Stamp},
+        GarbageGenerator,
+    },
+    mismatch::Mismatch,
+};
+
+/// Blocks with at most this many wrong bits count as bit flips.
+const MAX_BIT_FLIPS: u64 = 8;
+
+/// How far, in bytes, to look around a block for the data it holds.
+const SEARCH_DISTANCE: u64 = 256 * 1024;
+
+/// Granularity in bytes of the offsets at which to look for the data
+/// that a block holds.
+const SEARCH_STEP: usize = 512;
+
+/// What a block that did not read back as written holds instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Corruption {
+    /// Only zeroes.
+    Zeros,
+    /// Only 0xff bytes.
+    Ones,
+    /// The data that was written, with a few bits flipped.
+    BitFlips,
+    /// Data that was written for another offset (or another device).
+    /// Only recognized with stamps, or with generators that seek
+    /// quickly.
+    Misdirected,
+    /// Data from an earlier pass or run.
+    Stale,
+    /// Anything else.
+    Other,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Corruption::Zeros => "zeros",
+            Corruption::Ones => "0xff",
+            Corruption::BitFlips => "bit-flips",
+            Corruption::Misdirected => "misdirected",
+            Corruption::Stale => "stale",
+            Corruption::Other => "other",
+        })
+    }
+}
+
+/// Number of blocks with each kind of corruption.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CorruptionCounts(BTreeMap<Corruption, usize>);
+
+impl fmt::Display for CorruptionCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (corruption, count)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{corruption}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Classifies the blocks of a pass that did not read back as written.
+pub(crate) struct Classifier<'a> {
+    /// Generates the data that the pass wrote.
+    generator: Box<dyn GarbageGenerator>,
+    /// Generate the data that the earlier passes wrote, in order.
+    earlier: Vec<Box<dyn GarbageGenerator>>,
+    stamp: Option<Stamp>,
+    counts: &'a mut CorruptionCounts,
+    scratch: Vec<u8>,
+}
+
+impl<'a> Classifier<'a> {
+    pub(crate) fn new(
+        generator: Box<dyn GarbageGenerator>,
+        earlier: Vec<Box<dyn GarbageGenerator>>,
+        stamp: Option<Stamp>,
+        counts: &'a mut CorruptionCounts,
+    ) -> Self {
+        Self {
+            generator,
+            earlier,
+            stamp,
+            counts,
+            scratch: vec![],
+        }
+    }
+
+    /// Classify the block at `offset`, which was read back as `have`,
+    /// differing from what was written as described by `mismatch`.
+    pub(crate) fn classify(
+        &mut self,
+        offset: u64,
+        have: &[u8],
+        mismatch: &Mismatch,
+    ) -> anyhow::Result<Corruption> {
+        let corruption = self.find(offset, have, mismatch)?;
+        *self.counts.0.entry(corruption).or_default() += 1;
+        Ok(corruption)
+    }
+
+    fn find(
+        &mut self,
+        offset: u64,
+        have: &[u8],
+        mismatch: &Mismatch,
+    ) -> anyhow::Result<Corruption> {
+        if have.iter().all(|b| *b == 0) {
+            return Ok(Corruption::Zeros);
+        }
+        if have.iter().all(|b| *b == 0xff) {
+            return Ok(Corruption::Ones);
+        }
+        if mismatch.bits <= MAX_BIT_FLIPS {
+            return Ok(Corruption::BitFlips);
+        }
+        match self
+            .stamp
+            .as_ref()
+            .map(|stamp| stamp.diagnose(offset, have))
+        {
+            Some(Diagnosis::Misdirected { .. } | Diagnosis::OtherDevice { .. }) => {
+                return Ok(Corruption::Misdirected)
+            }
+            Some(Diagnosis::OtherRun { .. } | Diagnosis::StalePass { .. }) => {
+                return Ok(Corruption::Stale)
+            }
+            Some(Diagnosis::Corrupted) | None => {}
+        }
+        for earlier in &mut self.earlier {
+            if generates(earlier.as_mut(), offset, have, &mut self.scratch)? {
+                return Ok(Corruption::Stale);
+            }
+        }
+        if self.written_nearby(offset, have)? {
+            return Ok(Corruption::Misdirected);
+        }
+        Ok(Corruption::Other)
+    }
+
+    /// Whether `have` is the data that the pass wrote near `offset`,
+    /// but not at it.
+    ///
+    /// Only generators that seek quickly are searched: for the others,
+    /// going back to before every bad block would take as long as
+    /// generating the data up to it.
+    fn written_nearby(&mut self, offset: u64, have: &[u8]) -> anyhow::Result<bool> {
+        if !self.generator.seeks_quickly() {
+            return Ok(false);
+        }
+        let from = offset.saturating_sub(SEARCH_DISTANCE);
+        let len = (offset - from + SEARCH_DISTANCE) as usize + have.len();
+        self.scratch.resize(len, 0);
+        self.generator
+            .seek_to(from)
+            .and_then(|_| self.generator.read_exact(&mut self.scratch))
+            .context("Generating the data written around a block")?;
+        let written_at = (0..=len - have.len())
+            .step_by(SEARCH_STEP)
+            .filter(|at| from + *at as u64 != offset)
+            .find(|at| self.scratch[*at..*at + have.len()] == *have);
+        Ok(written_at.is_some())
+    }
+}
+
+/// Whether `generator` produces `data` at `offset`.
+fn generates(
+    generator: &mut dyn GarbageGenerator,
+    offset: u64,
+    data: &[u8],
+    scratch: &mut Vec<u8>,
+) -> anyhow::Result<bool> {
+    scratch.resize(data.len(), 0);
+    generator
+        .seek_to(offset)
+        .and_then(|_| generator.read_exact(scratch))
+        .context("Generating the data of an earlier pass")?;
+    Ok(scratch[..] == *data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::garbage::GarbageGeneratorVariant;
+    use test_case::test_case;
+
+    fn generator(variant: &GarbageGeneratorVariant) -> Box<dyn GarbageGenerator> {
+        variant.to_generator(1.into()).unwrap()
+    }
+
+    /// The data that `variant` generates for `len` bytes at `offset`.
+    fn data(variant: &GarbageGeneratorVariant, offset: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        let mut generator = generator(variant);
+        generator.seek_to(offset).unwrap();
+        generator.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test_case(|_| vec![0; 4096], Corruption::Zeros; "zeros")]
+    #[test_case(|_| vec![0xff; 4096], Corruption::Ones; "ones")]
+    #[test_case(|should| { let mut have = should.to_vec(); have[7] ^= 0x11; have }, Corruption::BitFlips; "bit flips")]
+    #[test_case(|_| data(&GarbageGeneratorVariant::Aes, 65536 + 512, 4096), Corruption::Misdirected; "misdirected")]
+    #[test_case(|_| data(&GarbageGeneratorVariant::Blake3, 65536, 4096), Corruption::Stale; "earlier pass")]
+    #[test_case(|should| should.iter().map(|b| b.wrapping_add(1)).collect(), Corruption::Other; "other")]
+    fn classifies(have: fn(&[u8]) -> Vec<u8>, expected: Corruption) {
+        let aes = GarbageGeneratorVariant::Aes;
+        let should = data(&aes, 65536, 4096);
+        let have = have(&should);
+        let mismatch = Mismatch::new(&have, &should).unwrap();
+        let mut counts = CorruptionCounts::default();
+        let earlier = vec![generator(&GarbageGeneratorVariant::Blake3)];
+        let mut classifier = Classifier::new(generator(&aes), earlier, None, &mut counts);
+        assert_eq!(
+            classifier.classify(65536, &have, &mismatch).unwrap(),
+            expected
+        );
+        assert_eq!(counts.to_string(), format!("{expected}: 1"));
+    }
+
+    #[test]
+    fn only_searches_generators_that_seek_quickly() {
+        let shishua = GarbageGeneratorVariant::Shishua;
+        let should = data(&shishua, 65536, 4096);
+        let have = data(&shishua, 65536 + 512, 4096);
+        let mismatch = Mismatch::new(&have, &should).unwrap();
+        let mut counts = CorruptionCounts::default();
+        let mut classifier = Classifier::new(generator(&shishua), vec![], None, &mut counts);
+        assert_eq!(
+            classifier.classify(65536, &have, &mismatch).unwrap(),
+            Corruption::Other
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Issuing the tests' I/O to the devices.
+//!
+//! Writes send the data in [`SharedIOBuffer`]s to the device, and reads
+//! fill [`IOBuffer`]s from it. An engine may keep several of these in
+//! flight at once, but always completes them in the order they were
+//! submitted, so that the tests don't need to care which engine runs
+//! their I/O.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    ops::{Deref, Range},
+    os::unix::fs::{FileExt as _, OpenOptionsExt as _},
+    path::Path,
+};
+
+use anyhow::Context as _;
+
+use crate::{IOBuffer, SharedIOBuffer, OPEN_FLAGS};
+
+#[cfg(target_os = "linux")]
+mod uring;
+
+/// The ways of issuing I/O to the devices.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum IoEngine {
+    /// One blocking read or write at a time.
+    #[default]
+    Blocking,
+
+    /// io_uring, with up to the queue depth of reads or writes in flight.
+    #[cfg(target_os = "linux")]
+    IoUring,
+}
+
+/// How to issue I/O to a device.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IoSettings {
+    pub engine: IoEngine,
+    /// Number of reads or writes that the engine may keep in flight.
+    pub queue_depth: usize,
+}
+
+/// A finished read or write.
+pub(crate) struct Done<B> {
+    pub offset: u64,
+    pub buf: B,
+    /// Transferring fewer bytes than the buffer holds (i.e., reaching
+    /// the end of the device) is an error of kind [`IoBuf::SHORT`].
+    pub result: io::Result<()>,
+}
+
+pub(crate) trait Engine<B: IoBuf> {
+    /// Whether another read or write can be submitted before the next
+    /// one is completed.
+    fn has_room(&self) -> bool;
+
+    /// Start transferring `buf` at `offset` on the device.
+    fn submit(&mut self, offset: u64, buf: B) -> io::Result<()>;
+
+    /// Wait for the oldest read or write that is still in flight to
+    /// finish, or return None if there is none.
+    fn complete(&mut self) -> io::Result<Option<Done<B>>>;
+
+    /// Transfer only the bytes in `part` of `buf`, at `offset` plus
+    /// the start of `part`, right away; other I/O may stay in flight.
+    fn transfer_part(&mut self, offset: u64, buf: &mut B, part: Range<usize>) -> io::Result<()>;
+
+    /// Make sure that everything written by the transfers that
+    /// completed so far is stored on the device (and not just in its
+    /// cache). Transfers that are still in flight may or may not be.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// A buffer that an engine can transfer to or from the device.
+pub(crate) trait IoBuf: Deref<Target = [u8]> + Send + 'static {
+    /// Whether transferring this buffer writes it to the device.
+    const WRITES: bool;
+
+    /// The kind of error for transferring fewer bytes than the buffer holds.
+    const SHORT: io::ErrorKind;
+
+    /// Transfer the bytes in `part` of the buffer, at `offset` plus the
+    /// start of `part`.
+    fn transfer_part_at(&mut self, file: &File, offset: u64, part: Range<usize>) -> io::Result<()>;
+
+    fn transfer_at(&mut self, file: &File, offset: u64) -> io::Result<()> {
+        let len = self.len();
+        self.transfer_part_at(file, offset, 0..len)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn uring_entry(&mut self, fd: i32, offset: u64) -> io_uring::squeue::Entry;
+}
+
+impl IoBuf for IOBuffer {
+    const WRITES: bool = false;
+    const SHORT: io::ErrorKind = io::ErrorKind::UnexpectedEof;
+
+    fn transfer_part_at(&mut self, file: &File, offset: u64, part: Range<usize>) -> io::Result<()> {
+        let at = offset + part.start as u64;
+        file.read_exact_at(&mut self[part], at)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn uring_entry(&mut self, fd: i32, offset: u64) -> io_uring::squeue::Entry {
+        use io_uring::{opcode, types};
+        opcode::Read::new(types::Fd(fd), self.as_mut_ptr(), self.len() as u32)
+            .offset(offset)
+            .build()
+    }
+}
+
+impl IoBuf for SharedIOBuffer {
+    const WRITES: bool = true;
+    const SHORT: io::ErrorKind = io::ErrorKind::WriteZero;
+
+    fn transfer_part_at(&mut self, file: &File, offset: u64, part: Range<usize>) -> io::Result<()> {
+        let at = offset + part.start as u64;
+        file.write_all_at(&self[part], at)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn uring_entry(&mut self, fd: i32, offset: u64) -> io_uring::squeue::Entry {
+        use io_uring::{opcode, types};
+        opcode::Write::new(types::Fd(fd), self.as_ptr(), self.len() as u32)
+            .offset(offset)
+            .build()
+    }
+}
+
+/// Open the device at `dev_path` for reading or writing `B`s with the
+/// engine selected in `io`.
+pub(crate) fn open<B: IoBuf>(
+    dev_path: &Path,
+    io: IoSettings,
+) -> anyhow::Result<Box<dyn Engine<B>>> {
+    let file = OpenOptions::new()
+        .read(!B::WRITES)
+        .write(B::WRITES)
+        .custom_flags(OPEN_FLAGS)
+        .open(dev_path)
+        .with_context(|| {
+            let purpose = if B::WRITES { "writing" } else { "reading" };
+            format!("Opening the device {dev_path:?} for {purpose}")
+        })?;
+    Ok(match io.engine {
+        IoEngine::Blocking => Box::new(Blocking { file, done: None }),
+        #[cfg(target_os = "linux")]
+        IoEngine::IoUring => {
+            Box::new(uring::Uring::new(file, io.queue_depth).context("Setting up io_uring")?)
+        }
+    })
+}
+
+/// Transfers each buffer right when it is submitted.
+struct Blocking<B> {
+    file: File,
+    done: Option<Done<B>>,
+}
+
+impl<B: IoBuf> Engine<B> for Blocking<B> {
+    fn has_room(&self) -> bool {
+        self.done.is_none()
+    }
+
+    fn submit(&mut self, offset: u64, mut buf: B) -> io::Result<()> {
+        assert!(self.done.is_none(), "Submitted I/O without room for it");
+        let result = buf.transfer_at(&self.file, offset);
+        self.done = Some(Done {
+            offset,
+            buf,
+            result,
+        });
+        Ok(())
+    }
+
+    fn complete(&mut self) -> io::Result<Option<Done<B>>> {
+        Ok(self.done.take())
+    }
+
+    fn transfer_part(&mut self, offset: u64, buf: &mut B, part: Range<usize>) -> io::Result<()> {
+        buf.transfer_part_at(&self.file, offset, part)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write as _;
+    use test_case::test_case;
+
+    fn buffer(len: usize, byte: u8) -> IOBuffer {
+        let mut buf = IOBuffer::with_capacity(len);
+        buf.resize(len, byte);
+        buf
+    }
+
+    /// Submit a transfer of each buffer, in order, keeping the queue full.
+    fn transfer_all<B: IoBuf>(engine: &mut dyn Engine<B>, bufs: Vec<B>) -> Vec<Done<B>> {
+        let mut done = vec![];
+        for (i, buf) in bufs.into_iter().enumerate() {
+            while !engine.has_room() {
+                done.push(engine.complete().unwrap().unwrap());
+            }
+            engine.submit(i as u64 * 4096, buf).unwrap();
+        }
+        while let Some(finished) = engine.complete().unwrap() {
+            done.push(finished);
+        }
+        done
+    }
+
+    #[test_case(IoEngine::Blocking; "blocking")]
+    #[cfg_attr(target_os = "linux", test_case(IoEngine::IoUring; "io_uring"))]
+    fn completes_in_order(engine: IoEngine) {
+        let io = IoSettings {
+            engine,
+            queue_depth: 4,
+        };
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[0; 16 * 4096]).unwrap();
+        let mut writer = match open::<SharedIOBuffer>(file.path(), io) {
+            Ok(writer) => writer,
+            // io_uring may be unavailable, e.g. in containers:
+            Err(e)
+                if engine == IoEngine::IoUring
+                    && e.root_cause()
+                        .downcast_ref::<io::Error>()
+                        .and_then(io::Error::raw_os_error)
+                        .is_some_and(|errno| [libc::ENOSYS, libc::EPERM].contains(&errno)) =>
+            {
+                return
+            }
+            Err(e) => panic!("Opening the engine: {e:#}"),
+        };
+        let written = (0..16).map(|i| buffer(4096, i).into_shared()).collect();
+        for (i, done) in transfer_all(&mut *writer, written).into_iter().enumerate() {
+            assert_eq!(done.offset, i as u64 * 4096);
+            done.result.unwrap();
+        }
+        drop(writer);
+
+        let mut reader = open::<IOBuffer>(file.path(), io).unwrap();
+        // One more than was written, to read past the end of the file:
+        let read = (0..17).map(|_| buffer(4096, 0xff)).collect();
+        let done = transfer_all(&mut *reader, read);
+        assert_eq!(done.len(), 17);
+        for (i, done) in done.into_iter().enumerate() {
+            assert_eq!(done.offset, i as u64 * 4096);
+            if i == 16 {
+                assert_eq!(done.result.unwrap_err().kind(), IOBuffer::SHORT);
+            } else {
+                done.result.unwrap();
+                assert!(done.buf.iter().all(|b| *b == i as u8), "block {i}");
+            }
+        }
+    }
+}
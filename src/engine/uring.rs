@@ -0,0 +1,121 @@
+//! An engine that keeps several reads or writes in flight with io_uring.
+
+use std::{collections::VecDeque, fs::File, io, ops::Range, os::fd::AsRawFd as _};
+
+use io_uring::IoUring;
+
+use super::{Done, Engine, IoBuf};
+
+pub(super) struct Uring<B> {
+    // Declared before `file`, so that the ring is torn down first.
+    ring: IoUring,
+    file: File,
+    queue_depth: usize,
+    /// The reads or writes in flight, in the order they were submitted.
+    in_flight: VecDeque<InFlight<B>>,
+    /// The sequence number of the first entry of `in_flight`.
+    first: u64,
+}
+
+struct InFlight<B> {
+    offset: u64,
+    /// The kernel transfers from or into this buffer until `result` is set.
+    buf: B,
+    result: Option<io::Result<()>>,
+}
+
+impl<B: IoBuf> Uring<B> {
+    pub(super) fn new(file: File, queue_depth: usize) -> io::Result<Self> {
+        Ok(Self {
+            ring: IoUring::new(queue_depth.try_into().unwrap_or(u32::MAX))?,
+            file,
+            queue_depth,
+            in_flight: VecDeque::with_capacity(queue_depth),
+            first: 0,
+        })
+    }
+
+    /// Wait for at least one read or write to finish, and record the
+    /// results of all that did.
+    fn wait(&mut self) -> io::Result<()> {
+        // Signals interrupt the wait, e.g. the SIGINT that stops the test cleanly:
+        while let Err(e) = self.ring.submit_and_wait(1) {
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        for entry in self.ring.completion() {
+            let index = (entry.user_data() - self.first) as usize;
+            let in_flight = &mut self.in_flight[index];
+            in_flight.result = Some(match entry.result() {
+                transferred if transferred < 0 => Err(io::Error::from_raw_os_error(-transferred)),
+                transferred if (transferred as usize) < in_flight.buf.len() => Err(io::Error::new(
+                    B::SHORT,
+                    "transferred fewer bytes than requested",
+                )),
+                _ => Ok(()),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<B: IoBuf> Engine<B> for Uring<B> {
+    fn has_room(&self) -> bool {
+        self.in_flight.len() < self.queue_depth
+    }
+
+    fn submit(&mut self, offset: u64, mut buf: B) -> io::Result<()> {
+        assert!(self.has_room(), "Submitted I/O without room for it");
+        let sequence = self.first + self.in_flight.len() as u64;
+        let entry = buf
+            .uring_entry(self.file.as_raw_fd(), offset)
+            .user_data(sequence);
+        // Safety: the buffer stays alive (and in place, on the heap) in
+        // `in_flight` until the kernel is done with it.
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        self.in_flight.push_back(InFlight {
+            offset,
+            buf,
+            result: None,
+        });
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    fn complete(&mut self) -> io::Result<Option<Done<B>>> {
+        while self.in_flight.front().is_some_and(|i| i.result.is_none()) {
+            self.wait()?;
+        }
+        let Some(in_flight) = self.in_flight.pop_front() else {
+            return Ok(None);
+        };
+        self.first += 1;
+        Ok(Some(Done {
+            offset: in_flight.offset,
+            buf: in_flight.buf,
+            result: in_flight.result.expect("Waited for the result"),
+        }))
+    }
+
+    fn transfer_part(&mut self, offset: u64, buf: &mut B, part: Range<usize>) -> io::Result<()> {
+        buf.transfer_part_at(&self.file, offset, part)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+impl<B> Drop for Uring<B> {
+    fn drop(&mut self) {
+        // The kernel may still use the buffers of unfinished I/O, so
+        // wait for it to finish before they can be freed:
+        let unfinished = self.in_flight.iter().filter(|i| i.result.is_none()).count();
+        if unfinished > 0 && self.ring.submit_and_wait(unfinished).is_err() {
+            // Better to leak the buffers than to risk the kernel writing into freed memory.
+            std::mem::forget(std::mem::take(&mut self.in_flight));
+        }
+    }
+}
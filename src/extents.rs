@@ -0,0 +1,131 @@
+//! Merging the blocks that did not read back as written into extents,
+//! so that a dying device doesn't drown the log in one line per block.
+
+use std::fmt;
+
+use crate::{classify::Corruption, rate_limit::RateLimit};
+
+/// A run of adjacent blocks of a pass that did not read back as
+/// written and hold the same kind of corruption.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Extent {
+    pub pass: usize,
+    /// Byte offset of the first block.
+    pub start: u64,
+    /// Length in bytes.
+    pub len: u64,
+    /// What the blocks hold instead, if that was looked into.
+    pub class: Option<Corruption>,
+}
+
+impl Extent {
+    /// Byte offset just past the last block.
+    pub(crate) fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+/// Displays the class, or "unclassified".
+pub(crate) struct Class(pub Option<Corruption>);
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(corruption) => corruption.fmt(f),
+            None => f.write_str("unclassified"),
+        }
+    }
+}
+
+/// The extents of a device that did not read back as written, over
+/// all passes, in the order they were found.
+#[derive(Debug, Default)]
+pub(crate) struct BadExtents {
+    /// The pass whose blocks are recorded.
+    pass: usize,
+    extents: Vec<Extent>,
+    /// Limits how many of the blocks that begin an extent are logged.
+    limit: RateLimit,
+}
+
+impl BadExtents {
+    /// Record the blocks of pass `pass` from now on.
+    pub(crate) fn start_pass(&mut self, pass: usize) {
+        self.pass = pass;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.extents.len()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Extent> {
+        self.extents.iter()
+    }
+
+    /// Record that the block of `len` bytes at `offset` holds `class`
+    /// instead of what was written. Blocks must be recorded in order.
+    ///
+    /// Returns whether to log the block, and if so, how many extents
+    /// were begun without being logged since the last one that was:
+    /// only blocks that begin an extent are logged, and only as often
+    /// as a [`RateLimit`] allows.
+    pub(crate) fn record(
+        &mut self,
+        offset: u64,
+        len: u64,
+        class: Option<Corruption>,
+    ) -> Option<usize> {
+        match self.extents.last_mut() {
+            Some(last) if last.pass == self.pass && last.end() == offset && last.class == class => {
+                last.len += len;
+                return None;
+            }
+            _ => self.extents.push(Extent {
+                pass: self.pass,
+                start: offset,
+                len,
+                class,
+            }),
+        }
+        self.limit.allow()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merges_adjacent_blocks_of_a_kind() {
+        let mut extents = BadExtents::default();
+        let zeros = Some(Corruption::Zeros);
+        assert_eq!(extents.record(0, 4096, zeros), Some(0));
+        assert_eq!(extents.record(4096, 4096, zeros), None);
+        // Not logged, as the last one was just now:
+        assert_eq!(extents.record(8192, 4096, Some(Corruption::Other)), None);
+        assert_eq!(extents.record(16384, 4096, Some(Corruption::Other)), None);
+        extents.start_pass(1);
+        assert_eq!(extents.record(20480, 4096, Some(Corruption::Other)), None);
+        let found: Vec<_> = extents
+            .iter()
+            .map(|extent| {
+                (
+                    extent.pass,
+                    extent.start,
+                    extent.len,
+                    Class(extent.class).to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (0, 0, 8192, "zeros".to_string()),
+                (0, 8192, 4096, "other".to_string()),
+                (0, 16384, 4096, "other".to_string()),
+                (1, 20480, 4096, "other".to_string()),
+            ]
+        );
+        assert_eq!(extents.limit.unlogged, 3);
+    }
+}
@@ -0,0 +1,28 @@
+//! Naming the files that are kept per device.
+
+/// The part of a file name that stands for the device named `device`
+/// (its serial number, or the name of its path): its characters that
+/// are safe in file names, with every other character replaced by '_'.
+pub(crate) fn for_device(device: &str) -> String {
+    device
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("WD-WCC4E1", "WD-WCC4E1"; "plain")]
+    #[test_case("WD-WCC/4E1", "WD-WCC_4E1"; "slash")]
+    #[test_case("disk.img", "disk.img"; "dot")]
+    #[test_case("Z1 ä:2", "Z1___2"; "space and non-ascii")]
+    fn keeps_safe_characters(device: &str, expected: &str) {
+        assert_eq!(for_device(device), expected);
+    }
+}
@@ -1,10 +1,29 @@
 mod aes;
 mod blake3;
+mod command;
+mod complement;
+mod compressible;
+pub(crate) mod kat;
+mod parallel;
+pub(crate) mod pattern;
+mod seed;
 mod shishua;
-use std::{fmt, io::Read, str::FromStr};
+pub(crate) mod stamp;
+pub(crate) mod token;
+
+pub use seed::Seed;
+use std::{
+    fmt,
+    io::{self, Read},
+    str::FromStr,
+};
+
+/// The unit in which generators define their output: the data for
+/// each unit only depends on its offset, not on how the output is read.
+pub(crate) const LOGICAL_UNIT: u64 = 4096;
 
 /// The method to use for generating deterministic "garbage" data
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub enum GarbageGeneratorVariant {
     #[cfg_attr(not(feature = "shishua-cli"), default)]
     /// AES, CTR mode with 128-bit little-endian counter.
@@ -13,10 +32,39 @@ pub enum GarbageGeneratorVariant {
     /// The BLAKE3 cryptographic hash function; slightly faster than AES on Apple Silicon hardware.
     Blake3,
 
+    /// The `shishua` RNG, computed in-process. Much faster than the cryptographic generators.
+    Shishua,
+
     #[cfg_attr(feature = "shishua-cli", default)]
     #[cfg(feature = "shishua-cli")]
     /// The `shishua` RNG, invoked via the cli tool of the same name.
     ShishuaCli,
+
+    /// A fixed byte pattern, repeated over the whole device (e.g. `pattern:aa`).
+    Pattern(Vec<u8>),
+
+    /// The output of an external program (e.g. `cmd:shishua --seed {seed_hex}`).
+    ///
+    /// The program's arguments are separated by whitespace (quote them
+    /// like in a shell to include whitespace), and may
+    /// contain the placeholders `{seed}` (decimal), `{seed_hex}` and
+    /// `{offset}`, the byte offset that the output should start at.
+    Command(String),
+
+    /// Pseudorandom data that compresses by a ratio, and optionally
+    /// also deduplicates by a ratio (e.g. `compressible:2.5` or
+    /// `compressible:2:4`).
+    Compressible {
+        ratio: compressible::Ratio,
+        dedup: u32,
+    },
+
+    /// The bitwise complement of another generator's output (e.g. `complement:aes`).
+    Complement(Box<GarbageGeneratorVariant>),
+
+    /// Whichever of the generators above is the fastest on this
+    /// machine, for the number of devices under test.
+    Auto,
 }
 
 impl fmt::Display for GarbageGeneratorVariant {
@@ -24,8 +72,22 @@ impl fmt::Display for GarbageGeneratorVariant {
         match self {
             GarbageGeneratorVariant::Aes => write!(f, "AES"),
             GarbageGeneratorVariant::Blake3 => write!(f, "BLAKE3"),
+            GarbageGeneratorVariant::Shishua => write!(f, "shishua"),
             #[cfg(feature = "shishua-cli")]
-            GarbageGeneratorVariant::ShishuaCli => write!(f, "shishua"),
+            GarbageGeneratorVariant::ShishuaCli => write!(f, "shishua-cli"),
+            GarbageGeneratorVariant::Pattern(pattern) => {
+                write!(f, "pattern:")?;
+                pattern.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+            }
+            GarbageGeneratorVariant::Compressible { ratio, dedup: 1 } => {
+                write!(f, "compressible:{ratio}")
+            }
+            GarbageGeneratorVariant::Compressible { ratio, dedup } => {
+                write!(f, "compressible:{ratio}:{dedup}")
+            }
+            GarbageGeneratorVariant::Command(template) => write!(f, "cmd:{template}"),
+            GarbageGeneratorVariant::Complement(inner) => write!(f, "complement:{inner}"),
+            GarbageGeneratorVariant::Auto => write!(f, "auto"),
         }
     }
 }
@@ -34,12 +96,49 @@ impl FromStr for GarbageGeneratorVariant {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(pattern) = s.to_lowercase().strip_prefix("pattern:") {
+            return Ok(GarbageGeneratorVariant::Pattern(pattern::parse_hex(
+                pattern,
+            )?));
+        }
+        // Program names and arguments are case-sensitive:
+        if let Some((prefix, template)) = s.split_once(':') {
+            if prefix.eq_ignore_ascii_case("cmd") {
+                return Ok(GarbageGeneratorVariant::Command(template.to_string()));
+            }
+        }
+        if let Some(ratios) = s.to_lowercase().strip_prefix("compressible:") {
+            let (ratio, dedup) = ratios.split_once(':').unwrap_or((ratios, "1"));
+            let dedup = dedup
+                .parse()
+                .ok()
+                .filter(|dedup| *dedup > 0)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Invalid dedup ratio {dedup:?}; expected a whole number of at least 1"
+                    )
+                })?;
+            return Ok(GarbageGeneratorVariant::Compressible {
+                ratio: ratio.parse()?,
+                dedup,
+            });
+        }
+        // The inner generator may be a case-sensitive command:
+        if let Some((prefix, inner)) = s.split_once(':') {
+            if prefix.eq_ignore_ascii_case("complement") {
+                return Ok(GarbageGeneratorVariant::Complement(Box::new(
+                    inner.parse()?,
+                )));
+            }
+        }
         match s.to_lowercase().as_str() {
             "aes" => Ok(GarbageGeneratorVariant::Aes),
             "blake3" => Ok(GarbageGeneratorVariant::Blake3),
+            "shishua" => Ok(GarbageGeneratorVariant::Shishua),
+            "auto" => Ok(GarbageGeneratorVariant::Auto),
 
             #[cfg(feature = "shishua-cli")]
-            "shishua" => Ok(GarbageGeneratorVariant::ShishuaCli),
+            "shishua-cli" => Ok(GarbageGeneratorVariant::ShishuaCli),
 
             _ => Err(anyhow::anyhow!("Unknown garbage generator variant {s}")),
         }
@@ -48,19 +147,295 @@ impl FromStr for GarbageGeneratorVariant {
 
 impl GarbageGeneratorVariant {
     /// Create a new garbage generator for the specified type. You need one for each write step and each read step, per device.
-    pub fn to_generator(self, block_size: usize, seed: u64) -> Box<dyn GarbageGenerator> {
+    pub fn to_generator(&self, seed: Seed) -> anyhow::Result<Box<dyn GarbageGenerator>> {
+        Ok(match self {
+            GarbageGeneratorVariant::Aes => Box::new(aes::AesGenerator::new(seed)),
+            GarbageGeneratorVariant::Blake3 => Box::new(blake3::Blake3Generator::new(seed)),
+            GarbageGeneratorVariant::Shishua => Box::new(shishua::ShishuaGenerator::new(seed)),
+            #[cfg(feature = "shishua-cli")]
+            GarbageGeneratorVariant::ShishuaCli => {
+                Box::new(command::CommandGenerator::new(shishua::CLI_TEMPLATE, seed)?)
+            }
+            GarbageGeneratorVariant::Pattern(pattern) => {
+                Box::new(pattern::PatternGenerator::new(pattern.clone()))
+            }
+            GarbageGeneratorVariant::Compressible { ratio, dedup } => Box::new(
+                compressible::CompressibleGenerator::new(seed, *ratio, *dedup),
+            ),
+            GarbageGeneratorVariant::Command(template) => {
+                Box::new(command::CommandGenerator::new(template, seed)?)
+            }
+            GarbageGeneratorVariant::Complement(inner) => Box::new(
+                complement::ComplementGenerator::new(inner.to_generator(seed)?),
+            ),
+            GarbageGeneratorVariant::Auto => {
+                anyhow::bail!("The auto generator must be resolved before use")
+            }
+        })
+    }
+
+    /// Like [`Self::to_generator`], but generating the data on
+    /// `threads` threads if the generator can do that. The output is
+    /// the same either way.
+    pub fn to_parallel_generator(
+        &self,
+        seed: Seed,
+        threads: usize,
+    ) -> anyhow::Result<Box<dyn GarbageGenerator>> {
         match self {
-            GarbageGeneratorVariant::Aes => Box::new(aes::AesGenerator::new(block_size, seed)),
-            GarbageGeneratorVariant::Blake3 => {
-                Box::new(blake3::Blake3Generator::new(block_size, seed))
+            // Only these can seek without generating everything up to the offset:
+            GarbageGeneratorVariant::Aes | GarbageGeneratorVariant::Blake3 if threads > 1 => {
+                let workers = (0..threads)
+                    .map(|_| self.to_generator(seed))
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Box::new(parallel::ParallelGenerator::new(workers)?))
             }
+            GarbageGeneratorVariant::Complement(inner) => Ok(Box::new(
+                complement::ComplementGenerator::new(inner.to_parallel_generator(seed, threads)?),
+            )),
+            _ => self.to_generator(seed),
+        }
+    }
+
+    /// The version of the generator's output format.
+    ///
+    /// Whenever a generator's output for a given seed changes, its
+    /// version must change too, since data written with the old output
+    /// can not be verified with the new one.
+    ///
+    /// The auto generator has no output of its own, so no version.
+    pub fn version(&self) -> anyhow::Result<u32> {
+        Ok(match self {
+            GarbageGeneratorVariant::Aes => 1,
+            // Version 1 derived its output per I/O buffer, rather than per logical unit:
+            GarbageGeneratorVariant::Blake3 => 2,
+            GarbageGeneratorVariant::Shishua => 1,
             #[cfg(feature = "shishua-cli")]
-            GarbageGeneratorVariant::ShishuaCli => Box::new(
-                shishua::ShishuaCliGenerator::new(seed).expect("shishua child process should work"),
-            ),
+            GarbageGeneratorVariant::ShishuaCli => 1,
+            GarbageGeneratorVariant::Pattern(_) => 1,
+            GarbageGeneratorVariant::Compressible { .. } => 1,
+            // Whatever the program outputs; it's up to the user to keep that stable.
+            GarbageGeneratorVariant::Command(_) => 1,
+            GarbageGeneratorVariant::Complement(inner) => inner.version()?,
+            GarbageGeneratorVariant::Auto => {
+                anyhow::bail!("The auto generator must be resolved before use")
+            }
+        })
+    }
+
+    /// The generators that produce pseudorandom data, and whose speed is worth measuring.
+    pub fn benchmarkable() -> Vec<Self> {
+        vec![
+            GarbageGeneratorVariant::Aes,
+            GarbageGeneratorVariant::Blake3,
+            GarbageGeneratorVariant::Shishua,
+            #[cfg(feature = "shishua-cli")]
+            GarbageGeneratorVariant::ShishuaCli,
+        ]
+    }
+
+    /// Replace [`GarbageGeneratorVariant::Auto`] with the fastest
+    /// generator for testing `devices` devices at once.
+    pub fn resolve(self, block_size: usize, devices: usize) -> anyhow::Result<Self> {
+        match self {
+            GarbageGeneratorVariant::Auto => crate::bench::fastest(block_size, devices),
+            GarbageGeneratorVariant::Complement(inner) => Ok(GarbageGeneratorVariant::Complement(
+                Box::new(inner.resolve(block_size, devices)?),
+            )),
+            other => Ok(other),
         }
     }
 }
 
+/// Derive the seed for a single device from the seed for the whole run
+/// and the device's stable identity (e.g. its serial number).
+///
+/// That way, each device receives different data, and data that ends
+/// up on the wrong device can not be mistaken for correct data.
+///
+/// The derived seed is as wide as `seed`.
+pub fn derive_device_seed(seed: Seed, identity: &str) -> Seed {
+    let mut hasher = ::blake3::Hasher::new_derive_key("disk-spinner 2025 per-device seed");
+    hasher.update(&seed.to_bytes());
+    hasher.update(identity.as_bytes());
+    let derived = hasher.finalize();
+    let derived = derived.as_bytes();
+    match seed {
+        Seed::Short(_) => Seed::Short(u64::from_le_bytes(derived[..8].try_into().unwrap())),
+        Seed::Key128(_) => Seed::Key128(derived[..16].try_into().unwrap()),
+        Seed::Key256(_) => Seed::Key256(*derived),
+    }
+}
+
 /// A type that allows garbage generation via its [`Read`] implementation.
-pub trait GarbageGenerator: Read {}
+pub trait GarbageGenerator: Read + Send {
+    /// Reposition the generator so that the next read returns the
+    /// data that starts `offset` bytes into its stream.
+    fn seek_to(&mut self, offset: u64) -> io::Result<()>;
+
+    /// Whether seeking takes about as long wherever it goes, rather
+    /// than generating everything up to the offset (e.g. when seeking
+    /// backwards).
+    fn seeks_quickly(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{GarbageGeneratorVariant, Seed};
+    use std::io::Read as _;
+    use test_case::test_case;
+
+    #[test_case(GarbageGeneratorVariant::Aes; "aes")]
+    #[test_case(GarbageGeneratorVariant::Blake3; "blake3")]
+    #[test_case(GarbageGeneratorVariant::Shishua; "shishua")]
+    #[test_case(GarbageGeneratorVariant::Pattern(vec![1, 2, 3]); "pattern")]
+    #[test_case(GarbageGeneratorVariant::Complement(Box::new(GarbageGeneratorVariant::Aes)); "complement")]
+    #[test_case("compressible:3:2".parse().unwrap(); "compressible")]
+    #[test_case(GarbageGeneratorVariant::Command("yes {seed}".to_string()); "command")]
+    fn seeking_matches_sequential_reads(variant: GarbageGeneratorVariant) {
+        let block_size = 4096;
+        let mut expected = vec![0; block_size * 16];
+        variant
+            .to_generator(23.into())
+            .unwrap()
+            .read_exact(&mut expected)
+            .unwrap();
+
+        let mut generator = variant.to_generator(23.into()).unwrap();
+        let mut actual = vec![0; block_size];
+        for block in [5, 2, 15, 0, 0, 9] {
+            generator.seek_to((block * block_size) as u64).unwrap();
+            generator.read_exact(&mut actual).unwrap();
+            assert_eq!(
+                &actual[..],
+                &expected[block * block_size..(block + 1) * block_size],
+                "block {block}"
+            );
+        }
+    }
+
+    #[test_case(GarbageGeneratorVariant::Aes; "aes")]
+    #[test_case(GarbageGeneratorVariant::Blake3; "blake3")]
+    #[test_case(GarbageGeneratorVariant::Shishua; "shishua")]
+    #[test_case(GarbageGeneratorVariant::Pattern(vec![1, 2, 3]); "pattern")]
+    #[test_case("compressible:1.5:3".parse().unwrap(); "compressible")]
+    fn read_size_does_not_matter(variant: GarbageGeneratorVariant) {
+        let mut expected = vec![0; 65536];
+        variant
+            .to_generator(23.into())
+            .unwrap()
+            .read_exact(&mut expected)
+            .unwrap();
+
+        for read_size in [1, 100, 512, 4096, 5000, 8192] {
+            let mut generator = variant.to_generator(23.into()).unwrap();
+            let mut actual = vec![0; 65536];
+            for piece in actual.chunks_mut(read_size) {
+                generator.read_exact(piece).unwrap();
+            }
+            assert!(expected == actual, "read size {read_size}");
+        }
+
+        // Also from an offset that is not aligned to anything:
+        let mut generator = variant.to_generator(23.into()).unwrap();
+        generator.seek_to(1001).unwrap();
+        let mut actual = vec![0; 10000];
+        generator.read_exact(&mut actual).unwrap();
+        assert!(expected[1001..11001] == actual[..]);
+    }
+
+    #[test_case(GarbageGeneratorVariant::Aes; "aes")]
+    #[test_case(GarbageGeneratorVariant::Blake3; "blake3")]
+    #[test_case(GarbageGeneratorVariant::Complement(Box::new(GarbageGeneratorVariant::Blake3)); "complement")]
+    fn parallel_matches_sequential(variant: GarbageGeneratorVariant) {
+        let len = 5 * 1024 * 1024 + 1234;
+        let mut expected = vec![0; len];
+        variant
+            .to_generator(23.into())
+            .unwrap()
+            .read_exact(&mut expected)
+            .unwrap();
+
+        let mut generator = variant.to_parallel_generator(23.into(), 3).unwrap();
+        let mut actual = vec![0; len];
+        for piece in actual.chunks_mut(100_000) {
+            generator.read_exact(piece).unwrap();
+        }
+        assert!(expected == actual);
+
+        generator.seek_to(1001).unwrap();
+        let mut actual = vec![0; 10000];
+        generator.read_exact(&mut actual).unwrap();
+        assert!(expected[1001..11001] == actual[..]);
+    }
+
+    #[test]
+    fn derives_distinct_device_seeds() {
+        let a = super::derive_device_seed(1.into(), "WDC_WD180EDGZ_2GH00001");
+        let b = super::derive_device_seed(1.into(), "WDC_WD180EDGZ_2GH00002");
+        assert_ne!(a, b);
+        assert_ne!(
+            a,
+            super::derive_device_seed(2.into(), "WDC_WD180EDGZ_2GH00001")
+        );
+        assert_eq!(
+            a,
+            super::derive_device_seed(1.into(), "WDC_WD180EDGZ_2GH00001")
+        );
+    }
+
+    #[test]
+    fn derives_seeds_as_wide_as_the_master_seed() {
+        let derived = super::derive_device_seed(Seed::Key128([1; 16]), "WDC_WD180EDGZ_2GH00001");
+        assert!(matches!(derived, Seed::Key128(key) if key != [1; 16]));
+        let derived = super::derive_device_seed(Seed::Key256([1; 32]), "WDC_WD180EDGZ_2GH00001");
+        assert!(matches!(derived, Seed::Key256(key) if key != [1; 32]));
+    }
+
+    #[test]
+    fn complement_inverts_every_bit() {
+        let mut plain = vec![0; 8192];
+        GarbageGeneratorVariant::Aes
+            .to_generator(5.into())
+            .unwrap()
+            .read_exact(&mut plain)
+            .unwrap();
+        let mut inverted = vec![0; 8192];
+        GarbageGeneratorVariant::Complement(Box::new(GarbageGeneratorVariant::Aes))
+            .to_generator(5.into())
+            .unwrap()
+            .read_exact(&mut inverted)
+            .unwrap();
+        assert!(plain
+            .iter()
+            .zip(inverted.iter())
+            .all(|(a, b)| a ^ b == 0xff));
+    }
+
+    #[test_case(GarbageGeneratorVariant::Auto; "auto")]
+    #[test_case(GarbageGeneratorVariant::Complement(Box::new(GarbageGeneratorVariant::Auto)); "complement of auto")]
+    fn unresolved_auto_is_an_error(variant: GarbageGeneratorVariant) {
+        assert!(variant.to_generator(5.into()).is_err());
+        assert!(variant.version().is_err());
+    }
+
+    #[test_case("aes"; "aes")]
+    #[test_case("pattern:aa55"; "pattern")]
+    #[test_case("complement:blake3"; "complement")]
+    #[test_case("compressible:2.5"; "compressible")]
+    #[test_case("compressible:2:8"; "compressible with dedup")]
+    #[test_case("cmd:MyTool --seed={seed_hex}"; "command")]
+    #[test_case("complement:cmd:/opt/MyGen --Seed '{seed} X'"; "complement of a command")]
+    fn display_roundtrips(s: &str) {
+        let variant: GarbageGeneratorVariant = s.parse().unwrap();
+        assert_eq!(
+            variant
+                .to_string()
+                .parse::<GarbageGeneratorVariant>()
+                .unwrap(),
+            variant
+        );
+    }
+}
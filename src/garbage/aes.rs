@@ -1,35 +1,45 @@
 //! Routines for generating an infinite amount of deterministic garbage.
 
-use aes::cipher::{KeyIvInit, StreamCipher};
-use rand::prelude::*;
-use rand_chacha::ChaCha8Rng;
+use aes::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
 use std::io;
 
-use super::GarbageGenerator;
+use super::{GarbageGenerator, Seed, LOGICAL_UNIT};
 
 type ActiveCipher = ctr::Ctr128LE<aes::Aes128>;
 
 /// A generator for deterministically random-looking garbage data.
+///
+/// The output is the AES-CTR keystream, which does not depend on how
+/// it is read.
 pub(crate) struct AesGenerator {
-    buf: Vec<u8>,
+    zeroes: Vec<u8>,
     cipher: ActiveCipher,
 }
 
-impl GarbageGenerator for AesGenerator {}
+impl GarbageGenerator for AesGenerator {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.cipher
+            .try_seek(offset)
+            .map_err(|e| io::Error::other(format!("crypto error {e:?}")))
+    }
+
+    fn seeks_quickly(&self) -> bool {
+        true
+    }
+}
 
 impl AesGenerator {
-    /// Generate a new AES garbage generator for a block size from a random seed.
-    pub(super) fn new(block_size: usize, seed: u64) -> Self {
-        let buf = vec![0; block_size];
-
-        let mut rng = ChaCha8Rng::seed_from_u64(seed);
-        let mut key = [0; 16];
-        let mut iv = [0; 16];
-        rng.fill_bytes(&mut key);
-        rng.fill_bytes(&mut iv);
-        let cipher = ActiveCipher::new(&key.into(), &iv.into());
-
-        Self { buf, cipher }
+    /// Generate a new AES garbage generator from a random seed.
+    ///
+    /// The key is the first half of the seed's key material, and the IV the second.
+    pub(super) fn new(seed: Seed) -> Self {
+        let zeroes = vec![0; LOGICAL_UNIT as usize];
+
+        let material = seed.key_material();
+        let (key, iv) = material.split_at(16);
+        let cipher = ActiveCipher::new(key.into(), iv.into());
+
+        Self { zeroes, cipher }
     }
 }
 
@@ -37,13 +47,11 @@ impl AesGenerator {
 /// with random data that can be copied to disk.
 impl io::Read for AesGenerator {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut done = 0;
-        for chunk in buf.chunks_exact_mut(self.buf.len()) {
+        for chunk in buf.chunks_mut(self.zeroes.len()) {
             self.cipher
-                .apply_keystream_b2b(&self.buf, chunk)
+                .apply_keystream_b2b(&self.zeroes[..chunk.len()], chunk)
                 .map_err(|e| io::Error::other(format!("crypto error {e:?}")))?;
-            done += chunk.len();
         }
-        Ok(done)
+        Ok(buf.len())
     }
 }
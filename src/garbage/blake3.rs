@@ -1,32 +1,33 @@
 use std::io;
 
-use rand::{RngCore as _, SeedableRng as _};
-use rand_chacha::ChaCha8Rng;
-
-use super::GarbageGenerator;
+use super::{GarbageGenerator, Seed, LOGICAL_UNIT};
 
+/// Generates each [`LOGICAL_UNIT`] of garbage as the keyed BLAKE3 XOF
+/// output for the unit's index.
 pub struct Blake3Generator {
-    buf: Vec<u8>,
     hasher: blake3::Hasher,
-    lba: usize,
+    position: u64,
 }
 
-impl GarbageGenerator for Blake3Generator {}
+impl GarbageGenerator for Blake3Generator {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.position = offset;
+        Ok(())
+    }
 
-impl Blake3Generator {
-    /// Generate a new Blake3 garbage generator for a block size from a random seed.
-    pub(super) fn new(block_size: usize, seed: u64) -> Self {
-        let buf = vec![0; block_size];
+    fn seeks_quickly(&self) -> bool {
+        true
+    }
+}
 
-        let mut rng = ChaCha8Rng::seed_from_u64(seed);
-        let mut key = [0; 32];
-        rng.fill_bytes(&mut key);
-        let hasher = blake3::Hasher::new_keyed(&key);
+impl Blake3Generator {
+    /// Generate a new Blake3 garbage generator from a random seed.
+    pub(super) fn new(seed: Seed) -> Self {
+        let hasher = blake3::Hasher::new_keyed(&seed.key_material());
 
         Self {
-            buf,
             hasher,
-            lba: 0,
+            position: 0,
         }
     }
 }
@@ -36,15 +37,19 @@ impl Blake3Generator {
 impl io::Read for Blake3Generator {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let mut done = 0;
-        for chunk in buf.chunks_exact_mut(self.buf.len()) {
-            let length = chunk.len();
-            let mut chunk = io::Cursor::new(chunk);
-            self.hasher.update(&self.lba.to_le_bytes());
-            let reader = self.hasher.finalize_xof();
-            io::copy(&mut reader.take(length.try_into().unwrap()), &mut chunk)?;
+        while done < buf.len() {
+            let unit = self.position / LOGICAL_UNIT;
+            let within_unit = self.position % LOGICAL_UNIT;
+            let length = (buf.len() - done).min((LOGICAL_UNIT - within_unit) as usize);
+
+            self.hasher.update(&unit.to_le_bytes());
+            let mut reader = self.hasher.finalize_xof();
+            reader.set_position(within_unit);
+            reader.fill(&mut buf[done..done + length]);
             self.hasher.reset();
+
             done += length;
-            self.lba += 1;
+            self.position += length as u64;
         }
         Ok(done)
     }
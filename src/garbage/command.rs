@@ -0,0 +1,228 @@
+//! Garbage generated by an external program.
+
+use std::{
+    io::{self, Read},
+    os::unix::process::CommandExt as _,
+    process::{Child, ChildStdout, Command, Stdio},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Context as _;
+
+use super::{GarbageGenerator, Seed};
+
+/// How much of the program's stderr to keep for error messages.
+const STDERR_LIMIT: usize = 16 * 1024;
+
+/// Runs a program that writes an endless stream of garbage to its
+/// stdout, built from a command line template. The template is split
+/// into arguments like a POSIX shell would (so arguments can be quoted),
+/// but no shell runs it.
+///
+/// The template may contain these placeholders:
+///
+/// * `{seed}`: the seed, in decimal (or hexadecimal, for 128- and
+///   256-bit keys).
+/// * `{seed_hex}`: the seed, in (upper case) hexadecimal.
+/// * `{offset}`: the byte offset that the program's output should
+///   start at. Programs that can't start at an offset should not use
+///   this; their output is then skipped up to the offset instead.
+pub(crate) struct CommandGenerator {
+    template: String,
+    seed: Seed,
+    process: Process,
+    /// The offset in the stream of the next byte to read from the program.
+    position: u64,
+}
+
+struct Process {
+    child: Child,
+    stdout: ChildStdout,
+    stderr: Option<JoinHandle<Vec<u8>>>,
+}
+
+impl CommandGenerator {
+    pub(super) fn new(template: &str, seed: Seed) -> anyhow::Result<Self> {
+        Ok(Self {
+            template: template.to_string(),
+            seed,
+            process: Process::spawn(template, seed, 0)?,
+            position: 0,
+        })
+    }
+
+    fn supports_offset(&self) -> bool {
+        self.template.contains("{offset}")
+    }
+
+    /// The error to report once the program's output ends.
+    fn exited(&mut self) -> io::Error {
+        let (status, stderr) = self.process.finish();
+        let stderr = String::from_utf8_lossy(&stderr);
+        io::Error::other(format!(
+            "Generator command {:?} stopped producing data at offset {} ({}); its stderr output was: {}",
+            self.template,
+            self.position,
+            status.map_or_else(|e| format!("could not get exit status: {e}"), |s| s.to_string()),
+            stderr.trim_end()
+        ))
+    }
+}
+
+impl Process {
+    fn spawn(template: &str, seed: Seed, offset: u64) -> anyhow::Result<Self> {
+        let args = shell_words::split(template)
+            .with_context(|| format!("Splitting the generator command {template:?}"))?;
+        let mut args = args.into_iter().map(|arg| {
+            arg.replace("{seed}", &seed.to_string())
+                .replace("{seed_hex}", &format!("{seed:X}"))
+                .replace("{offset}", &offset.to_string())
+        });
+        let program = args
+            .next()
+            .with_context(|| format!("Generator command {template:?} is empty"))?;
+        let mut child = Command::new(&program)
+            .args(args)
+            // In a process group of its own, so that stopping it also
+            // stops any programs that it started:
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Spawning the generator command {program:?}"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("Child process somehow has no stdout"))?;
+        let stderr = child
+            .stderr
+            .take()
+            .map(|stderr| thread::spawn(move || collect_stderr(stderr)));
+        Ok(Self {
+            child,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Stop the program (if it hasn't already), and return its exit
+    /// status and the tail of its stderr output.
+    fn finish(&mut self) -> (io::Result<std::process::ExitStatus>, Vec<u8>) {
+        self.kill();
+        let status = self.child.wait();
+        let stderr = self
+            .stderr
+            .take()
+            .and_then(|handle| handle.join().ok())
+            .unwrap_or_default();
+        (status, stderr)
+    }
+
+    /// Kill the program and everything it started, so that nothing
+    /// keeps its stderr open.
+    fn kill(&mut self) {
+        // The process group is still there (the unreaped program is in
+        // it), so this can't hit another one. Killing fails if all of
+        // the group exited already; that's fine.
+        // Safety: killpg has no memory safety requirements.
+        unsafe { libc::killpg(self.child.id() as libc::pid_t, libc::SIGKILL) };
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        self.kill();
+        let _ = self.child.wait();
+        if let Some(handle) = self.stderr.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Read all of `stderr`, keeping only the last [`STDERR_LIMIT`] bytes.
+fn collect_stderr(mut stderr: impl Read) -> Vec<u8> {
+    let mut collected = vec![];
+    let mut buf = [0; 4096];
+    while let Ok(read @ 1..) = stderr.read(&mut buf) {
+        collected.extend_from_slice(&buf[..read]);
+        if collected.len() > STDERR_LIMIT {
+            collected.drain(..collected.len() - STDERR_LIMIT);
+        }
+    }
+    collected
+}
+
+impl GarbageGenerator for CommandGenerator {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        if offset == self.position {
+            return Ok(());
+        }
+        if self.supports_offset() || offset < self.position {
+            let start = if self.supports_offset() { offset } else { 0 };
+            self.process = Process::spawn(&self.template, self.seed, start)
+                .map_err(|e| io::Error::other(format!("{e:#}")))?;
+            self.position = start;
+        }
+        let to_skip = offset - self.position;
+        io::copy(&mut Read::take(&mut *self, to_skip), &mut io::sink())?;
+        Ok(())
+    }
+
+    fn seeks_quickly(&self) -> bool {
+        self.supports_offset()
+    }
+}
+
+impl Read for CommandGenerator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.process.stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            return Err(self.exited());
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_program_output() {
+        let mut generator = CommandGenerator::new("yes {seed_hex}", 255.into()).unwrap();
+        let mut buf = [0; 9];
+        generator.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"FF\nFF\nFF\n");
+        generator.seek_to(1).unwrap();
+        generator.read_exact(&mut buf[..3]).unwrap();
+        assert_eq!(&buf[..3], b"F\nF");
+    }
+
+    #[test]
+    fn reports_exit_status_and_stderr() {
+        let mut generator =
+            CommandGenerator::new("sh -c 'echo oops >&2; exit 3'", 1.into()).unwrap();
+        let error = generator.read_exact(&mut [0; 16]).unwrap_err().to_string();
+        assert!(error.contains("exit status: 3"), "{error}");
+        assert!(error.contains("oops"), "{error}");
+    }
+
+    #[test]
+    fn stops_the_programs_it_started_when_dropped() {
+        // The shell passes its stderr on to `sleep`, which would keep
+        // the stderr thread waiting if it wasn't stopped too:
+        let generator = CommandGenerator::new("sh -c 'sleep 60 & yes'", 1.into()).unwrap();
+        let started = std::time::Instant::now();
+        drop(generator);
+        assert!(started.elapsed() < std::time::Duration::from_secs(30));
+    }
+
+    #[test]
+    fn reports_missing_programs() {
+        assert!(CommandGenerator::new("/nonexistent/generator {seed}", 1.into()).is_err());
+        assert!(CommandGenerator::new("", 1.into()).is_err());
+        assert!(CommandGenerator::new("'unterminated", 1.into()).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+//! Bitwise complement of another generator's output.
+
+use std::io;
+
+use super::GarbageGenerator;
+
+/// Inverts every bit that the inner generator produces, so that a
+/// pass with this generator stores the opposite value in every bit
+/// cell than a pass with the inner generator did.
+pub(crate) struct ComplementGenerator {
+    inner: Box<dyn GarbageGenerator>,
+}
+
+impl ComplementGenerator {
+    pub(super) fn new(inner: Box<dyn GarbageGenerator>) -> Self {
+        Self { inner }
+    }
+}
+
+impl GarbageGenerator for ComplementGenerator {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.inner.seek_to(offset)
+    }
+
+    fn seeks_quickly(&self) -> bool {
+        self.inner.seeks_quickly()
+    }
+}
+
+impl io::Read for ComplementGenerator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        for byte in &mut buf[..read] {
+            *byte = !*byte;
+        }
+        Ok(read)
+    }
+}
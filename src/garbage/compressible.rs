@@ -0,0 +1,175 @@
+//! A generator for data that compresses and deduplicates by a given
+//! ratio, for devices that behave differently with such data (SSDs,
+//! compressing RAID controllers, thin-provisioned volumes).
+
+use std::{fmt, io, str::FromStr};
+
+use super::{aes::AesGenerator, GarbageGenerator, Seed, LOGICAL_UNIT};
+
+/// A ratio of at least 1, with two decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ratio {
+    hundredths: u32,
+}
+
+impl Ratio {
+    /// Number of pseudorandom bytes in each unit, so that the unit
+    /// compresses by this ratio; the rest of the unit is zeroes.
+    fn random_bytes(&self) -> usize {
+        let unit = LOGICAL_UNIT as u32 * 100;
+        unit.div_ceil(self.hundredths) as usize
+    }
+}
+
+impl FromStr for Ratio {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid ratio {s:?}; expected a number like 2 or 1.5");
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        if fraction.len() > 2 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let whole: u32 = whole.parse().map_err(|_| invalid())?;
+        let fraction: u32 = format!("{fraction:0<2}").parse().map_err(|_| invalid())?;
+        let hundredths = whole
+            .checked_mul(100)
+            .and_then(|h| h.checked_add(fraction))
+            .ok_or_else(invalid)?;
+        if !(100..=LOGICAL_UNIT as u32 * 100).contains(&hundredths) {
+            anyhow::bail!("Ratio {s:?} must be between 1 and {LOGICAL_UNIT}");
+        }
+        Ok(Self { hundredths })
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (whole, fraction) = (self.hundredths / 100, self.hundredths % 100);
+        match fraction {
+            0 => write!(f, "{whole}"),
+            _ if fraction.is_multiple_of(10) => write!(f, "{whole}.{}", fraction / 10),
+            _ => write!(f, "{whole}.{fraction:02}"),
+        }
+    }
+}
+
+/// Produces units of [`LOGICAL_UNIT`] bytes that start with
+/// pseudorandom data and are padded with zeroes, so that they compress
+/// by the given ratio. Each run of `dedup` consecutive units holds
+/// identical data, so that they deduplicate by that ratio.
+pub(crate) struct CompressibleGenerator {
+    random: AesGenerator,
+    random_bytes: usize,
+    dedup: u64,
+    /// The data for the unit that `unit` was generated from.
+    unit: Vec<u8>,
+    /// The index of the deduplicated unit in `unit`, if any.
+    unit_source: Option<u64>,
+    position: u64,
+}
+
+impl CompressibleGenerator {
+    pub(super) fn new(seed: Seed, ratio: Ratio, dedup: u32) -> Self {
+        assert!(dedup > 0, "The dedup ratio must be at least 1");
+        Self {
+            random: AesGenerator::new(seed),
+            random_bytes: ratio.random_bytes(),
+            dedup: dedup.into(),
+            unit: vec![0; LOGICAL_UNIT as usize],
+            unit_source: None,
+            position: 0,
+        }
+    }
+
+    /// Generate the data for the unit that `source` is the index of,
+    /// after deduplication.
+    fn generate_unit(&mut self, source: u64) -> io::Result<()> {
+        if self.unit_source == Some(source) {
+            return Ok(());
+        }
+        self.unit_source = None;
+        self.random.seek_to(source * LOGICAL_UNIT)?;
+        io::Read::read_exact(&mut self.random, &mut self.unit[..self.random_bytes])?;
+        self.unit[self.random_bytes..].fill(0);
+        self.unit_source = Some(source);
+        Ok(())
+    }
+}
+
+impl GarbageGenerator for CompressibleGenerator {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.position = offset;
+        Ok(())
+    }
+
+    fn seeks_quickly(&self) -> bool {
+        true
+    }
+}
+
+impl io::Read for CompressibleGenerator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for piece in buf.chunks_mut(LOGICAL_UNIT as usize) {
+            // Pieces may straddle two units:
+            let mut done = 0;
+            while done < piece.len() {
+                let index = self.position / LOGICAL_UNIT;
+                let within = (self.position % LOGICAL_UNIT) as usize;
+                self.generate_unit(index / self.dedup)?;
+                let len = (piece.len() - done).min(self.unit.len() - within);
+                piece[done..done + len].copy_from_slice(&self.unit[within..within + len]);
+                done += len;
+                self.position += len as u64;
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read as _;
+    use test_case::test_case;
+
+    #[test_case("1", 4096; "incompressible")]
+    #[test_case("2", 2048; "half")]
+    #[test_case("2.5", 1639; "fraction")]
+    #[test_case("4096", 1; "maximum")]
+    fn random_bytes_match_ratio(ratio: &str, expected: usize) {
+        let ratio: Ratio = ratio.parse().unwrap();
+        assert_eq!(ratio.random_bytes(), expected);
+    }
+
+    #[test_case("0.5"; "below one")]
+    #[test_case("4097"; "above unit")]
+    #[test_case("1.234"; "too precise")]
+    #[test_case("two"; "not a number")]
+    #[test_case("-2"; "negative")]
+    fn rejects_invalid_ratios(ratio: &str) {
+        assert!(ratio.parse::<Ratio>().is_err());
+    }
+
+    #[test_case("1.5"; "one decimal")]
+    #[test_case("1.25"; "two decimals")]
+    #[test_case("3"; "whole")]
+    fn ratio_display_roundtrips(ratio: &str) {
+        assert_eq!(ratio.parse::<Ratio>().unwrap().to_string(), ratio);
+    }
+
+    #[test]
+    fn compresses_and_deduplicates() {
+        let mut generator = CompressibleGenerator::new(1.into(), "4".parse().unwrap(), 3);
+        let mut data = vec![0; 12 * 4096];
+        generator.read_exact(&mut data).unwrap();
+        let units: Vec<&[u8]> = data.chunks(4096).collect();
+        for unit in &units {
+            assert!(unit[1024..].iter().all(|b| *b == 0));
+            assert!(unit[..1024].iter().filter(|b| **b == 0).count() < 32);
+        }
+        assert_eq!(units[0], units[2]);
+        assert_ne!(units[2], units[3]);
+        assert_eq!(units[3], units[5]);
+    }
+}
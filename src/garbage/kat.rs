@@ -0,0 +1,216 @@
+//! Known-answer vectors for the garbage generators.
+//!
+//! A disk written by one build of disk-spinner has to be verifiable by
+//! any later build. These vectors pin down the output of each
+//! generator, so that a change to it (e.g. through a dependency
+//! update) can not go unnoticed.
+
+use std::io::Read as _;
+
+use anyhow::Context as _;
+use tracing::{error, info};
+
+use super::{GarbageGeneratorVariant, Seed};
+
+/// The BLAKE3 digest of `length` bytes that a generator produces at `offset`.
+pub(crate) struct KnownAnswer {
+    pub generator: &'static str,
+    /// The generator version that the vector applies to.
+    pub version: u32,
+    pub seed: Seed,
+    pub offset: u64,
+    pub length: usize,
+    pub digest: &'static str,
+}
+
+pub(crate) const KNOWN_ANSWERS: &[KnownAnswer] = &[
+    KnownAnswer {
+        generator: "aes",
+        version: 1,
+        seed: Seed::Short(0),
+        offset: 0,
+        length: 65536,
+        digest: "0201ea28d7d2741c734889551d2d33fe4c152ac00db56337f1e778d771d849c3",
+    },
+    KnownAnswer {
+        generator: "aes",
+        version: 1,
+        seed: Seed::Short(0xdeadbeef),
+        offset: 1 << 20,
+        length: 4096,
+        digest: "e79faba9a76c35c66210adeee90ab01a039301464181448d9466f3825a63c4c7",
+    },
+    KnownAnswer {
+        generator: "blake3",
+        version: 2,
+        seed: Seed::Short(0),
+        offset: 0,
+        length: 65536,
+        digest: "245d54b35d2cfaedcec02c43bd844774896f6e555b68c5bc74292de65798c578",
+    },
+    KnownAnswer {
+        generator: "blake3",
+        version: 2,
+        seed: Seed::Short(0xdeadbeef),
+        offset: 1 << 20,
+        length: 4096,
+        digest: "078055ef3911886f1d30bfc9eb07843893b0b305af32dfe17b08c9df1bd91dbc",
+    },
+    KnownAnswer {
+        generator: "shishua",
+        version: 1,
+        seed: Seed::Short(0),
+        offset: 0,
+        length: 65536,
+        digest: "e91b6457ef07bc4b6f7429ac5cbc8786a8914b9a80af2dc283e3e413a2610a38",
+    },
+    KnownAnswer {
+        generator: "shishua",
+        version: 1,
+        seed: Seed::Short(0xdeadbeef),
+        offset: 1 << 20,
+        length: 4096,
+        digest: "3b5db1de6f4759ef14002e4f9ff193ffed041ba23e2662b4b845f5c561889f26",
+    },
+    KnownAnswer {
+        generator: "pattern:aa55",
+        version: 1,
+        seed: Seed::Short(0),
+        offset: 1,
+        length: 4096,
+        digest: "cdf115a28712d52482b434f2ee5179de73ff6629a0c9f5703972a427dc0f689c",
+    },
+    KnownAnswer {
+        generator: "complement:aes",
+        version: 1,
+        seed: Seed::Short(0),
+        offset: 0,
+        length: 65536,
+        digest: "625a0897e154abd1aa81f3f6c97229bf876dc18fcaca197c35951250cd3cc555",
+    },
+    KnownAnswer {
+        generator: "compressible:2.5:3",
+        version: 1,
+        seed: Seed::Short(0xdeadbeef),
+        offset: 1 << 20,
+        length: 65536,
+        digest: "3492d1672a408d9b25b45879e68c460c07ae46c854e2f66e69bb1ab442ecd56a",
+    },
+    KnownAnswer {
+        generator: "aes",
+        version: 1,
+        seed: Seed::Key128(*b"disk-spinner kat"),
+        offset: 1 << 20,
+        length: 4096,
+        digest: "858df3c324c309703f5a2ab90b175e520452b82387eb417cfaedba7101cd16e9",
+    },
+    KnownAnswer {
+        generator: "aes",
+        version: 1,
+        seed: Seed::Key256(*b"disk-spinner known-answer vector"),
+        offset: 0,
+        length: 4096,
+        digest: "97058a426489dc712019e5b917bc259a41269adce4b2b6d808b7732a37ec8425",
+    },
+    KnownAnswer {
+        generator: "blake3",
+        version: 2,
+        seed: Seed::Key256(*b"disk-spinner known-answer vector"),
+        offset: 1 << 20,
+        length: 4096,
+        digest: "1f862500d214e94c4d63305366cad72134f5970708a7de9e6938a026b440ef1f",
+    },
+    KnownAnswer {
+        generator: "shishua",
+        version: 1,
+        seed: Seed::Key256(*b"disk-spinner known-answer vector"),
+        offset: 0,
+        length: 4096,
+        digest: "974c8bbbcb7d4a37758dd7e6aef952645b4e3cf64151038ee63fec169c1eee74",
+    },
+];
+
+impl KnownAnswer {
+    /// Check that `variant` produces the expected output for this vector.
+    pub(crate) fn check(&self, variant: &GarbageGeneratorVariant) -> anyhow::Result<()> {
+        let version = variant.version()?;
+        if version != self.version {
+            anyhow::bail!(
+                "Known answer is for version {} of {}, but the generator is at version {version}",
+                self.version,
+                self.generator,
+            );
+        }
+        let mut generator = variant.to_generator(self.seed)?;
+        generator
+            .seek_to(self.offset)
+            .context("Seeking to the known answer's offset")?;
+        let mut output = vec![0; self.length];
+        generator
+            .read_exact(&mut output)
+            .context("Generating pseudorandom data")?;
+        let digest = ::blake3::hash(&output).to_hex();
+        if digest.as_str() != self.digest {
+            anyhow::bail!(
+                "{} produced digest {digest} for seed={}, offset={}, length={}; expected {}",
+                self.generator,
+                self.seed,
+                self.offset,
+                self.length,
+                self.digest
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Check all known answers, for `disk-spinner selftest`.
+pub(crate) fn selftest() -> anyhow::Result<()> {
+    let mut failed = 0;
+    for known in KNOWN_ANSWERS {
+        let variant: GarbageGeneratorVariant = known.generator.parse()?;
+        // The CLI tool must produce the same output as the native
+        // generator (which it only does for short seeds):
+        #[cfg(feature = "shishua-cli")]
+        let variants = if variant == GarbageGeneratorVariant::Shishua
+            && matches!(known.seed, Seed::Short(_))
+        {
+            vec![variant, GarbageGeneratorVariant::ShishuaCli]
+        } else {
+            vec![variant]
+        };
+        #[cfg(not(feature = "shishua-cli"))]
+        let variants = vec![variant];
+        for variant in variants {
+            match known.check(&variant) {
+                Ok(()) => info!(
+                    generator = %variant,
+                    version = variant.version().ok(),
+                    seed = %known.seed,
+                    offset = known.offset,
+                    "Known answer matches"
+                ),
+                Err(e) => {
+                    error!(generator = %variant, error = %e, "Known answer does not match");
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} known answers did not match. Do not use this build to verify disks written by another build.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::KNOWN_ANSWERS;
+
+    #[test]
+    fn generators_produce_known_answers() {
+        for known in KNOWN_ANSWERS {
+            known.check(&known.generator.parse().unwrap()).unwrap();
+        }
+    }
+}
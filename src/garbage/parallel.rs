@@ -0,0 +1,96 @@
+//! Generating one stream on several threads at once.
+//!
+//! Generators that can seek cheaply (like the counter-based AES and
+//! BLAKE3 generators) can produce separate ranges of their stream
+//! independently; this splits the stream into chunks that are
+//! generated in parallel and then handed out in order.
+
+use std::io::{self, Read};
+
+use rayon::prelude::*;
+
+use super::GarbageGenerator;
+
+/// Number of bytes each thread generates at a time.
+const CHUNK: usize = 1024 * 1024;
+
+pub(crate) struct ParallelGenerator {
+    /// One generator per thread, each covering every n-th chunk.
+    workers: Vec<Box<dyn GarbageGenerator>>,
+    /// Dedicated threads, so that generating never waits for the
+    /// global pool, whose threads may all be busy with device I/O.
+    pool: rayon::ThreadPool,
+    batch: Vec<u8>,
+    /// Stream offset of the first byte in `batch`.
+    batch_start: u64,
+    /// Number of bytes in `batch` that hold generated data.
+    batch_len: usize,
+    /// Stream offset of the next byte to read.
+    position: u64,
+}
+
+impl ParallelGenerator {
+    /// Generate with `workers.len()` threads. All workers must be
+    /// identical generators.
+    pub(super) fn new(workers: Vec<Box<dyn GarbageGenerator>>) -> anyhow::Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(workers.len())
+            .thread_name(|i| format!("generator-{i}"))
+            .build()?;
+        Ok(Self {
+            batch: vec![0; CHUNK * workers.len()],
+            workers,
+            pool,
+            batch_start: 0,
+            batch_len: 0,
+            position: 0,
+        })
+    }
+
+    /// Generate the batch of data that starts at the current position.
+    fn refill(&mut self) -> io::Result<()> {
+        let start = self.position;
+        // Forget the old batch first, in case generating the new one fails.
+        self.batch_len = 0;
+        let (batch, workers) = (&mut self.batch, &mut self.workers);
+        self.pool.install(|| {
+            batch
+                .par_chunks_mut(CHUNK)
+                .zip(workers.par_iter_mut())
+                .enumerate()
+                .try_for_each(|(i, (chunk, worker))| {
+                    worker.seek_to(start + (i * CHUNK) as u64)?;
+                    worker.read_exact(chunk)
+                })
+        })?;
+        self.batch_start = start;
+        self.batch_len = self.batch.len();
+        Ok(())
+    }
+}
+
+impl GarbageGenerator for ParallelGenerator {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        // The next read regenerates the batch if it doesn't cover the new offset.
+        self.position = offset;
+        Ok(())
+    }
+
+    fn seeks_quickly(&self) -> bool {
+        self.workers.iter().all(|worker| worker.seeks_quickly())
+    }
+}
+
+impl Read for ParallelGenerator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let batch_end = self.batch_start + self.batch_len as u64;
+        if self.position < self.batch_start || self.position >= batch_end {
+            self.refill()?;
+        }
+        let from = (self.position - self.batch_start) as usize;
+        let read = buf.len().min(self.batch_len - from);
+        buf[..read].copy_from_slice(&self.batch[from..from + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
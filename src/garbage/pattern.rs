@@ -0,0 +1,98 @@
+//! A generator that repeats a fixed byte pattern, like `badblocks -w` does.
+
+use std::io;
+
+use super::GarbageGenerator;
+
+/// The patterns that `badblocks -w` writes, in order.
+pub(crate) const BADBLOCKS_PATTERNS: [u8; 4] = [0xaa, 0x55, 0xff, 0x00];
+
+pub(crate) struct PatternGenerator {
+    pattern: Vec<u8>,
+    position: u64,
+}
+
+impl PatternGenerator {
+    pub(super) fn new(pattern: Vec<u8>) -> Self {
+        assert!(!pattern.is_empty(), "Patterns must not be empty");
+        Self {
+            pattern,
+            position: 0,
+        }
+    }
+}
+
+impl GarbageGenerator for PatternGenerator {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.position = offset;
+        Ok(())
+    }
+
+    fn seeks_quickly(&self) -> bool {
+        true
+    }
+}
+
+impl io::Read for PatternGenerator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = (self.position % self.pattern.len() as u64) as usize;
+        let repeated = self.pattern[start..]
+            .iter()
+            .chain(self.pattern.iter().cycle());
+        for (dest, byte) in buf.iter_mut().zip(repeated) {
+            *dest = *byte;
+        }
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
+
+/// Parse a hex string like "aa55" into the bytes it represents.
+pub(super) fn parse_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if s.is_empty() || !s.len().is_multiple_of(2) {
+        anyhow::bail!("Pattern {s:?} must have a non-zero, even number of hex digits");
+    }
+    // Also keeps the slicing below on character boundaries:
+    if let Some(c) = s.chars().find(|c| !c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid hex in pattern {s:?}: {c:?} is not a hex digit");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read as _;
+    use test_case::test_case;
+
+    #[test_case("aa", &[0xaa]; "single byte")]
+    #[test_case("0xDEADbeef", &[0xde, 0xad, 0xbe, 0xef]; "with prefix")]
+    fn parses_hex(s: &str, expected: &[u8]) {
+        assert_eq!(parse_hex(s).unwrap(), expected);
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case("abc"; "odd length")]
+    #[test_case("zz"; "not hex")]
+    #[test_case("aéb"; "not ascii")]
+    fn rejects_invalid_hex(s: &str) {
+        assert!(parse_hex(s).is_err());
+    }
+
+    #[test]
+    fn repeats_across_reads() {
+        let mut generator = PatternGenerator::new(vec![1, 2, 3]);
+        let mut buf = [0; 4];
+        generator.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 1]);
+        generator.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [2, 3, 1, 2]);
+        generator.seek_to(1).unwrap();
+        generator.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [2, 3, 1, 2]);
+    }
+}
@@ -0,0 +1,157 @@
+//! Seeds for the garbage generators.
+
+use std::{fmt, str::FromStr};
+
+use rand::{RngCore as _, SeedableRng as _};
+use rand_chacha::ChaCha8Rng;
+
+/// What a generator's output is derived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seed {
+    /// A 64-bit number, which each generator expands into its keys.
+    Short(u64),
+
+    /// A full 128-bit key.
+    Key128([u8; 16]),
+
+    /// A full 256-bit key.
+    Key256([u8; 32]),
+}
+
+impl Seed {
+    /// 256 bits of key material for the generators that need keys.
+    ///
+    /// Short seeds are expanded with ChaCha8; keys are used as they
+    /// are, with 128-bit keys padded with zeroes.
+    pub(crate) fn key_material(&self) -> [u8; 32] {
+        let mut material = [0; 32];
+        match self {
+            Seed::Short(seed) => ChaCha8Rng::seed_from_u64(*seed).fill_bytes(&mut material),
+            Seed::Key128(key) => material[..16].copy_from_slice(key),
+            Seed::Key256(key) => material.copy_from_slice(key),
+        }
+        material
+    }
+
+    /// The seed as a sequence of bytes, for hashing.
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        match self {
+            Seed::Short(seed) => seed.to_le_bytes().to_vec(),
+            Seed::Key128(key) => key.to_vec(),
+            Seed::Key256(key) => key.to_vec(),
+        }
+    }
+}
+
+impl From<u64> for Seed {
+    fn from(seed: u64) -> Self {
+        Seed::Short(seed)
+    }
+}
+
+/// Short seeds are given as decimal (or 0x-prefixed hex) numbers,
+/// keys as exactly 32 or 64 hex digits.
+impl FromStr for Seed {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Invalid seed {s:?}; expected a 64-bit number or 32 or 64 hex digits (a 128- or 256-bit key)"
+            )
+        };
+        let digits = s.strip_prefix("0x");
+        match digits.unwrap_or(s).len() {
+            32 => Ok(Seed::Key128(
+                super::pattern::parse_hex(s)
+                    .map_err(|_| invalid())?
+                    .try_into()
+                    .unwrap(),
+            )),
+            64 => Ok(Seed::Key256(
+                super::pattern::parse_hex(s)
+                    .map_err(|_| invalid())?
+                    .try_into()
+                    .unwrap(),
+            )),
+            _ => match digits {
+                Some(digits) => u64::from_str_radix(digits, 16),
+                None => s.parse(),
+            }
+            .map(Seed::Short)
+            .map_err(|_| invalid()),
+        }
+    }
+}
+
+/// Short seeds in decimal, keys in (lower case) hex.
+impl fmt::Display for Seed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Seed::Short(seed) => write!(f, "{seed}"),
+            Seed::Key128(_) | Seed::Key256(_) => self
+                .to_bytes()
+                .iter()
+                .try_for_each(|byte| write!(f, "{byte:02x}")),
+        }
+    }
+}
+
+/// Short seeds and keys in upper case hex, e.g. for the `shishua` CLI tool.
+impl fmt::UpperHex for Seed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Seed::Short(seed) => write!(f, "{seed:X}"),
+            Seed::Key128(_) | Seed::Key256(_) => self
+                .to_bytes()
+                .iter()
+                .try_for_each(|byte| write!(f, "{byte:02X}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("1234", Seed::Short(1234); "decimal")]
+    #[test_case("0xff", Seed::Short(255); "short hex")]
+    #[test_case("00000000000000000000000000000001", Seed::Key128(core::array::from_fn(|i| (i == 15) as u8)); "128 bit of decimal digits")]
+    #[test_case("000102030405060708090a0b0c0d0e0f", Seed::Key128(core::array::from_fn(|i| i as u8)); "128 bit")]
+    #[test_case("0x000102030405060708090A0B0C0D0E0F101112131415161718191a1b1c1d1e1f", Seed::Key256(core::array::from_fn(|i| i as u8)); "256 bit")]
+    fn parses(s: &str, expected: Seed) {
+        assert_eq!(s.parse::<Seed>().unwrap(), expected);
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case("0x"; "only prefix")]
+    #[test_case("abc"; "short hex without prefix")]
+    #[test_case("000102030405060708090a0b0c0d0e"; "120 bit")]
+    #[test_case("000102030405060708090a0b0c0d0e0g"; "not hex")]
+    #[test_case("000102030405060708090a0b0c0d0eé"; "128 bit with a multibyte character")]
+    #[test_case("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1eé"; "256 bit with a multibyte character")]
+    fn rejects_invalid(s: &str) {
+        assert!(s.parse::<Seed>().is_err());
+    }
+
+    #[test_case(Seed::Short(u64::MAX); "short")]
+    #[test_case(Seed::Key128([0xab; 16]); "128 bit")]
+    #[test_case(Seed::Key256([0x01; 32]); "256 bit")]
+    fn display_roundtrips(seed: Seed) {
+        assert_eq!(seed.to_string().parse::<Seed>().unwrap(), seed);
+    }
+
+    #[test]
+    fn short_seeds_expand_like_before() {
+        // The AES generator used to fill its key and then its IV from ChaCha8:
+        let mut rng = ChaCha8Rng::seed_from_u64(42);
+        let mut key = [0; 16];
+        let mut iv = [0; 16];
+        rng.fill_bytes(&mut key);
+        rng.fill_bytes(&mut iv);
+        let material = Seed::Short(42).key_material();
+        assert_eq!(material[..16], key);
+        assert_eq!(material[16..], iv);
+    }
+}
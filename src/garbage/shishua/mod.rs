@@ -1,5 +1,8 @@
-#[cfg_attr(not(feature = "shishua-cli"), allow(dead_code))]
-mod cli;
+mod native;
 
+pub use native::ShishuaGenerator;
+
+/// The command line for running the `shishua` CLI tool as a
+/// [`super::command::CommandGenerator`].
 #[cfg(feature = "shishua-cli")]
-pub use cli::ShishuaCliGenerator;
+pub(crate) const CLI_TEMPLATE: &str = "shishua --seed {seed_hex}";
@@ -0,0 +1,355 @@
+//! A pure-Rust port of the [shishua](https://github.com/espadrine/shishua) RNG.
+//!
+//! The byte stream is the same as the one emitted by the `shishua`
+//! CLI tool for the same seed, so disks written with
+//! `--generator=shishua-cli` can be verified with this generator and
+//! vice versa.
+
+use std::io;
+
+use crate::garbage::{GarbageGenerator, Seed};
+
+/// Number of bytes that one round of the generator produces.
+const BLOCK: usize = 128;
+
+/// A 256-bit SIMD register, as four 64-bit lanes.
+type Lane = [u64; 4];
+
+/// The hex digits of Φ, used to initialize the generator state.
+const PHI: [u64; 16] = [
+    0x9E3779B97F4A7C15,
+    0xF39CC0605CEDC834,
+    0x1082276BF3A27251,
+    0xF86C6A11D0C18E95,
+    0x2767F0B153D27B7F,
+    0x0347045B5BF1827F,
+    0x01886F0928403002,
+    0xC1D64BA40F335E36,
+    0xF06AD7AE9717877E,
+    0x85839D6EFFBD7DC6,
+    0x64D325D1C5371682,
+    0xCADD0CCCFDFFBBE1,
+    0x626E33B8D04B4331,
+    0xBBF73C790D94F79D,
+    0x471C4AB3ED3D82A5,
+    0xFEC507705E4AE6E5,
+];
+
+/// Increment applied to the counter after each round.
+const INCREMENT: Lane = [7, 5, 3, 1];
+
+/// Number of rounds that are discarded when seeding the generator.
+const INIT_ROUNDS: usize = 13;
+
+#[derive(Clone, Default)]
+struct State {
+    state: [Lane; 4],
+    output: [Lane; 4],
+    counter: Lane,
+}
+
+impl State {
+    fn new(seed: [u64; 4]) -> Self {
+        let mut s = State {
+            state: [
+                [PHI[0] ^ seed[0], PHI[1], PHI[2] ^ seed[1], PHI[3]],
+                [PHI[4] ^ seed[2], PHI[5], PHI[6] ^ seed[3], PHI[7]],
+                [PHI[8] ^ seed[2], PHI[9], PHI[10] ^ seed[3], PHI[11]],
+                [PHI[12] ^ seed[0], PHI[13], PHI[14] ^ seed[1], PHI[15]],
+            ],
+            ..Default::default()
+        };
+        for _ in 0..INIT_ROUNDS {
+            s.round();
+            s.state = [s.output[3], s.output[2], s.output[1], s.output[0]];
+        }
+        s
+    }
+
+    /// Fill `out` (a multiple of [`BLOCK`] in length) with output.
+    fn generate(&mut self, out: &mut [u8]) {
+        debug_assert_eq!(out.len() % BLOCK, 0);
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") {
+            // Safety: we just checked that the CPU supports AVX2.
+            unsafe { avx2::generate(self, out) };
+            return;
+        }
+        self.generate_portable(out);
+    }
+
+    fn generate_portable(&mut self, out: &mut [u8]) {
+        for block in out.chunks_exact_mut(BLOCK) {
+            for (lane, dest) in self.output.iter().zip(block.chunks_exact_mut(32)) {
+                for (word, dest) in lane.iter().zip(dest.chunks_exact_mut(8)) {
+                    dest.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+            self.round();
+        }
+    }
+
+    /// Advance the state by one round, computing the next output block.
+    fn round(&mut self) {
+        let [s0, s1, s2, s3] = &mut self.state;
+        *s1 = add(s1, &self.counter);
+        *s3 = add(s3, &self.counter);
+        self.counter = add(&self.counter, &INCREMENT);
+
+        let u0 = shr(s0, 1);
+        let u1 = shr(s1, 3);
+        let u2 = shr(s2, 1);
+        let u3 = shr(s3, 3);
+        let t0 = rotate_u32s(s0, 5);
+        let t1 = rotate_u32s(s1, 3);
+        let t2 = rotate_u32s(s2, 5);
+        let t3 = rotate_u32s(s3, 3);
+        *s0 = add(&t0, &u0);
+        *s1 = add(&t1, &u1);
+        *s2 = add(&t2, &u2);
+        *s3 = add(&t3, &u3);
+
+        self.output = [xor(&u0, &t1), xor(&u2, &t3), xor(s0, s3), xor(s2, s1)];
+    }
+}
+
+fn add(a: &Lane, b: &Lane) -> Lane {
+    std::array::from_fn(|i| a[i].wrapping_add(b[i]))
+}
+
+fn xor(a: &Lane, b: &Lane) -> Lane {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+fn shr(a: &Lane, n: u32) -> Lane {
+    a.map(|x| x >> n)
+}
+
+/// The portable equivalent of `_mm256_permutevar8x32_epi32` with the
+/// index vector `[by, by+1, ..., by+7] mod 8`.
+fn rotate_u32s(a: &Lane, by: usize) -> Lane {
+    let words: [u32; 8] = std::array::from_fn(|i| (a[i / 2] >> (32 * (i % 2))) as u32);
+    std::array::from_fn(|i| {
+        let lo = words[(2 * i + by) % 8] as u64;
+        let hi = words[(2 * i + 1 + by) % 8] as u64;
+        lo | (hi << 32)
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use super::{State, BLOCK};
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn load(lane: &super::Lane) -> __m256i {
+        _mm256_loadu_si256(lane.as_ptr() as *const __m256i)
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn store(lane: &mut super::Lane, v: __m256i) {
+        _mm256_storeu_si256(lane.as_mut_ptr() as *mut __m256i, v)
+    }
+
+    /// AVX2 version of [`State::generate`]; this is a straight
+    /// transliteration of the reference implementation.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn generate(s: &mut State, out: &mut [u8]) {
+        let (mut o0, mut o1, mut o2, mut o3) = (
+            load(&s.output[0]),
+            load(&s.output[1]),
+            load(&s.output[2]),
+            load(&s.output[3]),
+        );
+        let (mut s0, mut s1, mut s2, mut s3) = (
+            load(&s.state[0]),
+            load(&s.state[1]),
+            load(&s.state[2]),
+            load(&s.state[3]),
+        );
+        let mut counter = load(&s.counter);
+        let shu0 = _mm256_set_epi32(4, 3, 2, 1, 0, 7, 6, 5);
+        let shu1 = _mm256_set_epi32(2, 1, 0, 7, 6, 5, 4, 3);
+        let increment = load(&super::INCREMENT);
+
+        for block in out.chunks_exact_mut(BLOCK) {
+            let dest = block.as_mut_ptr() as *mut __m256i;
+            _mm256_storeu_si256(dest, o0);
+            _mm256_storeu_si256(dest.add(1), o1);
+            _mm256_storeu_si256(dest.add(2), o2);
+            _mm256_storeu_si256(dest.add(3), o3);
+
+            s1 = _mm256_add_epi64(s1, counter);
+            s3 = _mm256_add_epi64(s3, counter);
+            counter = _mm256_add_epi64(counter, increment);
+
+            let u0 = _mm256_srli_epi64(s0, 1);
+            let u1 = _mm256_srli_epi64(s1, 3);
+            let u2 = _mm256_srli_epi64(s2, 1);
+            let u3 = _mm256_srli_epi64(s3, 3);
+            let t0 = _mm256_permutevar8x32_epi32(s0, shu0);
+            let t1 = _mm256_permutevar8x32_epi32(s1, shu1);
+            let t2 = _mm256_permutevar8x32_epi32(s2, shu0);
+            let t3 = _mm256_permutevar8x32_epi32(s3, shu1);
+            s0 = _mm256_add_epi64(t0, u0);
+            s1 = _mm256_add_epi64(t1, u1);
+            s2 = _mm256_add_epi64(t2, u2);
+            s3 = _mm256_add_epi64(t3, u3);
+
+            o0 = _mm256_xor_si256(u0, t1);
+            o1 = _mm256_xor_si256(u2, t3);
+            o2 = _mm256_xor_si256(s0, s3);
+            o3 = _mm256_xor_si256(s2, s1);
+        }
+
+        store(&mut s.output[0], o0);
+        store(&mut s.output[1], o1);
+        store(&mut s.output[2], o2);
+        store(&mut s.output[3], o3);
+        store(&mut s.state[0], s0);
+        store(&mut s.state[1], s1);
+        store(&mut s.state[2], s2);
+        store(&mut s.state[3], s3);
+        store(&mut s.counter, counter);
+    }
+}
+
+/// A generator for the shishua RNG that runs in-process.
+pub struct ShishuaGenerator {
+    seed: [u64; 4],
+    /// Number of bytes returned from `read` so far.
+    position: u64,
+    state: State,
+    /// Output that was generated but not yet returned from `read`.
+    pending: [u8; BLOCK],
+    pending_start: usize,
+}
+
+/// shishua can only be run forward, so seeking regenerates (and
+/// discards) all the data up to the requested offset. Seeking backwards
+/// starts over from the beginning of the stream.
+impl GarbageGenerator for ShishuaGenerator {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        if offset < self.position {
+            *self = Self::from_words(self.seed);
+        }
+        let to_skip = offset - self.position;
+        io::copy(&mut io::Read::take(&mut *self, to_skip), &mut io::sink())?;
+        Ok(())
+    }
+}
+
+impl ShishuaGenerator {
+    /// Create a shishua generator whose output matches `shishua --seed <seed as hex>`
+    /// for short seeds. Keys are used as four little-endian 64-bit words.
+    pub fn new(seed: Seed) -> Self {
+        let seed = match seed {
+            Seed::Short(seed) => [seed, 0, 0, 0],
+            Seed::Key128(_) | Seed::Key256(_) => {
+                let material = seed.key_material();
+                core::array::from_fn(|i| {
+                    u64::from_le_bytes(material[i * 8..(i + 1) * 8].try_into().unwrap())
+                })
+            }
+        };
+        Self::from_words(seed)
+    }
+
+    fn from_words(seed: [u64; 4]) -> Self {
+        Self {
+            seed,
+            position: 0,
+            state: State::new(seed),
+            pending: [0; BLOCK],
+            pending_start: BLOCK,
+        }
+    }
+}
+
+impl io::Read for ShishuaGenerator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pending = &self.pending[self.pending_start..];
+        let from_pending = pending.len().min(buf.len());
+        buf[..from_pending].copy_from_slice(&pending[..from_pending]);
+        self.pending_start += from_pending;
+
+        let rest = &mut buf[from_pending..];
+        let bulk = rest.len() - rest.len() % BLOCK;
+        self.state.generate(&mut rest[..bulk]);
+
+        let tail = &mut rest[bulk..];
+        if !tail.is_empty() {
+            self.state.generate(&mut self.pending);
+            tail.copy_from_slice(&self.pending[..tail.len()]);
+            self.pending_start = tail.len();
+        }
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read as _;
+    use test_case::test_case;
+
+    #[test]
+    fn portable_matches_simd() {
+        let mut simd = State::new([0xdeadbeef, 1, 2, 3]);
+        let mut portable = simd.clone();
+        let mut simd_out = vec![0; BLOCK * 64];
+        simd.generate(&mut simd_out);
+
+        let mut portable_out = vec![0; BLOCK * 64];
+        portable.generate_portable(&mut portable_out);
+        assert_eq!(simd_out, portable_out);
+    }
+
+    #[test_case(1; "single bytes")]
+    #[test_case(100; "short of a block")]
+    #[test_case(300; "across blocks")]
+    #[test_case(4096; "page sized")]
+    fn read_size_does_not_matter(chunk: usize) {
+        let mut expected = vec![0; 8192];
+        ShishuaGenerator::new(42.into())
+            .read_exact(&mut expected)
+            .unwrap();
+
+        let mut generator = ShishuaGenerator::new(42.into());
+        let mut actual = vec![0; 8192];
+        for piece in actual.chunks_mut(chunk) {
+            generator.read_exact(piece).unwrap();
+        }
+        assert_eq!(expected, actual);
+    }
+
+    /// Output of the reference implementation's `prng_init` and
+    /// `prng_gen` (the AVX2 version in shishua.h), built as a C program.
+    #[test_case([0; 4], 0, "955d96f90fb4aa53092d82e63a7c09e22ca5a4a5a75a5a39dc68b4125de7ce2b6b6efef58bd9cc4212dd744e81fd18b958f0625d38efcc1b6fdb0da336f7e5ee"; "zero seed")]
+    #[test_case([0x0123456789abcdef, 0xfedcba9876543210, 0x0f1e2d3c4b5a6978, 0x8796a5b4c3d2e1f0], 1 << 20, "c4dbba844d81b7b08ef980e715761703620f9b50355e6ab442a0ec6e7dcb8bfcb2f94a440698c74b251277081d6a9462121030624a392d239476da1b458a1384"; "full seed, 1MiB in")]
+    fn matches_reference(seed: [u64; 4], offset: u64, expected: &str) {
+        let mut generator = ShishuaGenerator::from_words(seed);
+        generator.seek_to(offset).unwrap();
+        let mut actual = [0; 64];
+        generator.read_exact(&mut actual).unwrap();
+        let actual: String = actual.iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[cfg(feature = "shishua-cli")]
+    #[test]
+    #[ignore = "needs the shishua CLI tool"]
+    fn matches_cli() {
+        let mut cli = crate::garbage::GarbageGeneratorVariant::ShishuaCli
+            .to_generator(0x1234.into())
+            .expect("The shishua CLI tool is installed");
+        let mut expected = vec![0; 65536];
+        cli.read_exact(&mut expected).unwrap();
+        let mut actual = vec![0; 65536];
+        ShishuaGenerator::new(0x1234.into())
+            .read_exact(&mut actual)
+            .unwrap();
+        assert_eq!(expected, actual);
+    }
+}
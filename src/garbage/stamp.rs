@@ -0,0 +1,264 @@
+//! Self-describing blocks: a small header stamped onto the garbage
+//! data at regular intervals, identifying where and when it was
+//! written.
+//!
+//! When the read test finds data it did not expect, the headers in
+//! that data tell misdirected writes, data left over from an earlier
+//! run or pass and data from a different (cross-wired) disk apart from
+//! plain corruption.
+
+use std::{fmt, io};
+
+use super::GarbageGenerator;
+
+/// Distance in bytes between two headers in the stream.
+pub(crate) const STAMP_INTERVAL: u64 = 4096;
+
+/// Length of a header in bytes; it replaces the first bytes of each
+/// [`STAMP_INTERVAL`]-sized unit of the pseudorandom stream.
+const HEADER_LEN: usize = 64;
+
+const MAGIC: &[u8; 8] = b"DSPINv1\0";
+
+/// Number of bytes of the device serial that fit into a header.
+const SERIAL_LEN: usize = 24;
+
+/// What identifies a single write pass over a device.
+#[derive(Debug, Clone)]
+pub(crate) struct Stamp {
+    /// Randomly chosen for each invocation of disk-spinner.
+    pub run_id: u64,
+    /// Incremented for each write pass over the same device.
+    pub pass: u32,
+    /// The device serial number, if known. Only the last 24 bytes are
+    /// recorded, as that is where the unique part of udev's serials is.
+    pub serial: [u8; SERIAL_LEN],
+}
+
+impl Stamp {
+    pub(crate) fn new(run_id: u64, pass: u32, serial: Option<&str>) -> Self {
+        let mut recorded = [0; SERIAL_LEN];
+        let serial = serial.unwrap_or_default().as_bytes();
+        let tail = &serial[serial.len().saturating_sub(SERIAL_LEN)..];
+        recorded[..tail.len()].copy_from_slice(tail);
+        Self {
+            run_id,
+            pass,
+            serial: recorded,
+        }
+    }
+
+    /// Stamp the output of `generator` with headers.
+    pub(crate) fn wrap(&self, generator: Box<dyn GarbageGenerator>) -> Box<dyn GarbageGenerator> {
+        Box::new(StampedGenerator {
+            inner: generator,
+            stamp: self.clone(),
+            position: 0,
+        })
+    }
+
+    fn header(&self, offset: u64) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..16].copy_from_slice(&offset.to_le_bytes());
+        header[16..24].copy_from_slice(&self.run_id.to_le_bytes());
+        header[24..28].copy_from_slice(&self.pass.to_le_bytes());
+        header[32..56].copy_from_slice(&self.serial);
+        let checksum = blake3::hash(&header[..56]);
+        header[56..64].copy_from_slice(&checksum.as_bytes()[..8]);
+        header
+    }
+
+    /// Examine the headers in `data`, which was read from `offset` on
+    /// the device and did not match what was written there, and
+    /// determine the most likely cause.
+    pub(crate) fn diagnose(&self, offset: u64, data: &[u8]) -> Diagnosis {
+        let mut diagnosis = Diagnosis::Corrupted;
+        for (unit_start, at) in header_positions(offset, data.len()) {
+            let Some(header) = data.get(at..at + HEADER_LEN) else {
+                continue;
+            };
+            let found = match Header::parse(header) {
+                None => Diagnosis::Corrupted,
+                Some(h) if h.offset != unit_start => Diagnosis::Misdirected {
+                    written_for: h.offset,
+                },
+                Some(h) if h.serial != self.serial => Diagnosis::OtherDevice {
+                    serial: String::from_utf8_lossy(&h.serial)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                },
+                Some(h) if h.run_id != self.run_id => Diagnosis::OtherRun { run_id: h.run_id },
+                Some(h) if h.pass != self.pass => Diagnosis::StalePass { pass: h.pass },
+                Some(_) => continue,
+            };
+            if found != Diagnosis::Corrupted {
+                return found;
+            }
+            diagnosis = found;
+        }
+        diagnosis
+    }
+}
+
+/// Returns the stream offsets of all headers that start within
+/// `len` bytes from `offset`, along with their position in the buffer.
+fn header_positions(offset: u64, len: usize) -> impl Iterator<Item = (u64, usize)> {
+    let first = offset.div_ceil(STAMP_INTERVAL) * STAMP_INTERVAL;
+    (first..offset + len as u64)
+        .step_by(STAMP_INTERVAL as usize)
+        .map(move |unit_start| (unit_start, (unit_start - offset) as usize))
+}
+
+struct Header {
+    offset: u64,
+    run_id: u64,
+    pass: u32,
+    serial: [u8; SERIAL_LEN],
+}
+
+impl Header {
+    /// Parse a header, returning None if it is not intact.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if &bytes[0..8] != MAGIC || blake3::hash(&bytes[..56]).as_bytes()[..8] != bytes[56..64] {
+            return None;
+        }
+        Some(Self {
+            offset: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            run_id: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            pass: u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+            serial: bytes[32..56].try_into().unwrap(),
+        })
+    }
+}
+
+/// The likely cause of a block not containing the expected data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Diagnosis {
+    /// The headers are intact, but the data is not what was written
+    /// (or there are no intact headers in the block at all).
+    Corrupted,
+
+    /// The block holds data that was meant for another offset.
+    Misdirected { written_for: u64 },
+
+    /// The block holds data that was written to a different device.
+    OtherDevice { serial: String },
+
+    /// The block holds data from a different invocation of disk-spinner.
+    OtherRun { run_id: u64 },
+
+    /// The block holds data from an earlier pass of this run.
+    StalePass { pass: u32 },
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnosis::Corrupted => write!(f, "corrupted"),
+            Diagnosis::Misdirected { written_for } => {
+                write!(f, "misdirected write, meant for offset {written_for}")
+            }
+            Diagnosis::OtherDevice { serial } => {
+                write!(f, "data written to another device (serial {serial:?})")
+            }
+            Diagnosis::OtherRun { run_id } => write!(f, "stale data from run {run_id:#x}"),
+            Diagnosis::StalePass { pass } => write!(f, "stale data from pass {pass}"),
+        }
+    }
+}
+
+/// A generator that overwrites the start of each [`STAMP_INTERVAL`]
+/// of its inner generator's output with a header.
+struct StampedGenerator {
+    inner: Box<dyn GarbageGenerator>,
+    stamp: Stamp,
+    position: u64,
+}
+
+impl GarbageGenerator for StampedGenerator {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.inner.seek_to(offset)?;
+        self.position = offset;
+        Ok(())
+    }
+
+    fn seeks_quickly(&self) -> bool {
+        self.inner.seeks_quickly()
+    }
+}
+
+impl io::Read for StampedGenerator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let start = self.position;
+        let end = start + read as u64;
+        // Also cover a header that started before this read:
+        let first_unit = start / STAMP_INTERVAL * STAMP_INTERVAL;
+        for unit_start in (first_unit..end).step_by(STAMP_INTERVAL as usize) {
+            let header = self.stamp.header(unit_start);
+            let header_end = unit_start + HEADER_LEN as u64;
+            if header_end <= start {
+                continue;
+            }
+            let from = start.max(unit_start);
+            let to = end.min(header_end);
+            buf[(from - start) as usize..(to - start) as usize]
+                .copy_from_slice(&header[(from - unit_start) as usize..(to - unit_start) as usize]);
+        }
+        self.position = end;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::garbage::GarbageGeneratorVariant;
+    use std::io::Read as _;
+    use test_case::test_case;
+
+    fn stamped(stamp: &Stamp, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        stamp
+            .wrap(GarbageGeneratorVariant::Aes.to_generator(1.into()).unwrap())
+            .read_exact(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn read_size_does_not_matter() {
+        let stamp = Stamp::new(1, 0, Some("serial"));
+        let expected = stamped(&stamp, 16384);
+        let mut generator =
+            stamp.wrap(GarbageGeneratorVariant::Aes.to_generator(1.into()).unwrap());
+        let mut actual = vec![0; 16384];
+        for piece in actual.chunks_mut(512) {
+            generator.read_exact(piece).unwrap();
+        }
+        assert_eq!(expected, actual);
+        assert_eq!(&actual[4096..4104], MAGIC);
+    }
+
+    #[test_case(Stamp::new(2, 0, Some("serial")), 0, Diagnosis::OtherRun { run_id: 2 }; "other run")]
+    #[test_case(Stamp::new(1, 1, Some("serial")), 0, Diagnosis::StalePass { pass: 1 }; "stale pass")]
+    #[test_case(Stamp::new(1, 0, Some("other")), 0, Diagnosis::OtherDevice { serial: "other".to_string() }; "other device")]
+    #[test_case(Stamp::new(1, 0, Some("serial")), 8192, Diagnosis::Misdirected { written_for: 0 }; "misdirected")]
+    fn diagnoses(found_stamp: Stamp, found_offset: usize, expected: Diagnosis) {
+        let ours = Stamp::new(1, 0, Some("serial"));
+        let found = stamped(&found_stamp, 16384);
+        assert_eq!(ours.diagnose(found_offset as u64, &found[..4096]), expected);
+    }
+
+    #[test]
+    fn diagnoses_corruption() {
+        let stamp = Stamp::new(1, 0, Some("serial"));
+        let mut found = stamped(&stamp, 8192);
+        found[4096 + 12] ^= 0x10;
+        assert_eq!(stamp.diagnose(4096, &found[4096..]), Diagnosis::Corrupted);
+        found[4096 + 12] ^= 0x10;
+        found[4096 + 1000] ^= 0x10;
+        assert_eq!(stamp.diagnose(4096, &found[4096..]), Diagnosis::Corrupted);
+    }
+}
@@ -0,0 +1,112 @@
+//! Run tokens: everything needed to generate the data of a write pass
+//! again, in one string that can be copied from the log.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::Context as _;
+
+use super::{GarbageGeneratorVariant, Seed, LOGICAL_UNIT};
+
+/// Identifies the format of the token itself.
+const PREFIX: &str = "dspin1";
+
+/// The generator and seed that a pass wrote its data with.
+///
+/// Formatted as `dspin1.<version>.<unit>.<seed>.<generator>`, where
+/// the version and logical unit size make sure that a token is only
+/// accepted by builds whose generator produces the same data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunToken {
+    pub generator: GarbageGeneratorVariant,
+    pub seed: Seed,
+}
+
+impl fmt::Display for RunToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.generator.version() {
+            Ok(version) => write!(
+                f,
+                "{PREFIX}.{version}.{LOGICAL_UNIT}.{}.{}",
+                self.seed, self.generator
+            ),
+            // Not a token that parses, as it could not reproduce any data:
+            Err(_) => write!(
+                f,
+                "{PREFIX}.unresolved.{LOGICAL_UNIT}.{}.{}",
+                self.seed, self.generator
+            ),
+        }
+    }
+}
+
+impl FromStr for RunToken {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid run token {s:?}; expected {PREFIX}.<version>.<unit>.<seed>.<generator>"
+            )
+        };
+        // The generator comes last, as it may contain dots itself:
+        let mut parts = s.splitn(5, '.');
+        let mut next = || parts.next().with_context(invalid);
+        if next()? != PREFIX {
+            anyhow::bail!(invalid());
+        }
+        let version: u32 = next()?.parse().with_context(invalid)?;
+        let unit: u64 = next()?.parse().with_context(invalid)?;
+        let seed: Seed = next()?.parse().with_context(invalid)?;
+        let generator: GarbageGeneratorVariant = next()?.parse().with_context(invalid)?;
+
+        if generator == GarbageGeneratorVariant::Auto {
+            anyhow::bail!("Run token {s:?} does not name a specific generator");
+        }
+        if unit != LOGICAL_UNIT {
+            anyhow::bail!(
+                "Run token {s:?} is for a logical unit of {unit} bytes, but this build uses {LOGICAL_UNIT}"
+            );
+        }
+        let current = generator.version()?;
+        if version != current {
+            anyhow::bail!(
+                "Run token {s:?} is for version {version} of the {generator} generator, but this build has version {current}"
+            );
+        }
+        Ok(Self { generator, seed })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(GarbageGeneratorVariant::Aes, Seed::Short(42); "short seed")]
+    #[test_case(GarbageGeneratorVariant::Blake3, Seed::Key256([7; 32]); "wide seed")]
+    #[test_case("compressible:2.5:3".parse().unwrap(), Seed::Key128([7; 16]); "generator with dots")]
+    #[test_case("cmd:my-rng --seed={seed_hex}".parse().unwrap(), Seed::Short(1); "command")]
+    fn roundtrips(generator: GarbageGeneratorVariant, seed: Seed) {
+        let token = RunToken { generator, seed };
+        assert_eq!(token.to_string().parse::<RunToken>().unwrap(), token);
+    }
+
+    #[test]
+    fn formats_compactly() {
+        let token = RunToken {
+            generator: GarbageGeneratorVariant::Aes,
+            seed: Seed::Short(42),
+        };
+        assert_eq!(token.to_string(), "dspin1.1.4096.42.AES");
+    }
+
+    #[test_case("dspin1.1.4096.42"; "missing generator")]
+    #[test_case("dspin2.1.4096.42.aes"; "other format")]
+    #[test_case("dspin1.1.512.42.aes"; "other unit")]
+    #[test_case("dspin1.1.4096.42.blake3"; "other version")]
+    #[test_case("dspin1.1.4096.42.auto"; "auto")]
+    #[test_case("dspin1.1.4096.x.aes"; "invalid seed")]
+    fn rejects(token: &str) {
+        assert!(token.parse::<RunToken>().is_err());
+    }
+}
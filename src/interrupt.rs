@@ -0,0 +1,33 @@
+//! Stopping the tests cleanly on SIGINT (Ctrl-C) or SIGTERM.
+//!
+//! The first signal asks all device tests to stop at the next block
+//! boundary, once their I/O in flight has finished; they then report
+//! how far they got. A second signal exits right away.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Context as _;
+use tracing::warn;
+
+/// Exit status of a run that was interrupted, as shells report for
+/// processes that were killed by SIGINT.
+pub(crate) const EXIT_STATUS: i32 = 130;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Handle SIGINT and SIGTERM by asking the tests to stop.
+pub(crate) fn install_handler() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            warn!("Interrupted again, exiting right away");
+            std::process::exit(EXIT_STATUS);
+        }
+        warn!("Interrupted; stopping the tests at the next block boundary. Interrupt again to exit right away.");
+    })
+    .context("Installing the interrupt handler")
+}
+
+/// Whether the tests should stop.
+pub(crate) fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
@@ -1,7 +1,7 @@
 extern crate block_utils;
 use super::child_partitions;
 use crate::Args;
-use aligned_buffer::UniqueAlignedBuffer;
+use aligned_buffer::{SharedAlignedBuffer, UniqueAlignedBuffer};
 use std::{
     path::{Path, PathBuf},
     str::FromStr,
@@ -9,6 +9,7 @@ use std::{
 use tracing::warn;
 
 pub type IOBuffer = UniqueAlignedBuffer<4096>;
+pub type SharedIOBuffer = SharedAlignedBuffer<4096>;
 
 pub const OPEN_FLAGS: i32 = libc::O_DIRECT | libc::O_EXCL;
 
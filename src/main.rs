@@ -1,19 +1,26 @@
+use std::fs;
 use std::fs::OpenOptions;
 use std::io;
 use std::io::Seek as _;
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::path::Path;
 use std::path::PathBuf;
 
 use anyhow::Context;
 use clap::Parser;
+use clap::Subcommand;
+use engine::IoEngine;
+use engine::IoSettings;
+use garbage::token::RunToken;
 use garbage::GarbageGeneratorVariant;
+use garbage::Seed;
 use indicatif::ProgressStyle;
 use rand::prelude::*;
 use rand::rng;
-use rayon::iter::Either;
+use range::Position;
 use rayon::prelude::*;
 use tracing::error;
-use tracing::info;
+use tracing::warn;
 use tracing_indicatif::IndicatifLayer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -21,8 +28,23 @@ use tracing_subscriber::util::SubscriberInitExt;
 #[macro_use]
 extern crate lazy_static;
 
+mod bad_block_list;
+mod bad_sectors;
+mod bench;
+mod checkpoint;
+mod classify;
+mod engine;
+mod extents;
+mod file_name;
 mod garbage;
+mod interrupt;
+mod mismatch;
+mod pipeline;
+mod range;
+mod rate_limit;
 mod read_test;
+mod recheck;
+mod run;
 mod write_test;
 
 mod linux;
@@ -31,6 +53,8 @@ use linux::sanity_checks;
 #[cfg(target_os = "linux")]
 use linux::IOBuffer;
 #[cfg(target_os = "linux")]
+use linux::SharedIOBuffer;
+#[cfg(target_os = "linux")]
 use linux::ValidDevice;
 #[cfg(target_os = "linux")]
 use linux::OPEN_FLAGS;
@@ -42,13 +66,18 @@ use other_os::sanity_checks;
 #[cfg(not(target_os = "linux"))]
 use other_os::IOBuffer;
 #[cfg(not(target_os = "linux"))]
+use other_os::SharedIOBuffer;
+#[cfg(not(target_os = "linux"))]
 use other_os::ValidDevice;
 #[cfg(not(target_os = "linux"))]
 use other_os::OPEN_FLAGS;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 pub(crate) struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Name of the devices to test.
     ///
     /// Each should be a mechanical disk block device (e.g. /dev/sda,
@@ -62,12 +91,166 @@ pub(crate) struct Args {
     #[clap(long)]
     buffer_size: Option<usize>,
 
-    #[clap(long, default_value_t, value_parser = clap::value_parser!(GarbageGeneratorVariant))]
-    generator: GarbageGeneratorVariant,
+    /// Generator for the test data: aes, blake3, shishua, auto,
+    /// pattern:<hex>, compressible:<ratio>[:<dedup ratio>],
+    /// complement:<generator> or cmd:<command line>.
+    ///
+    /// "auto" picks the generator that is fastest on this machine when
+    /// testing as many devices as were given.
+    ///
+    /// "cmd" runs a program that writes the data to its stdout. Its
+    /// arguments may contain {seed}, {seed_hex} and {offset} (the byte
+    /// offset that the output should start at), e.g.
+    /// "cmd:shishua --seed {seed_hex}". They are split at whitespace,
+    /// except where quoted as in a shell, but no shell runs them.
+    ///
+    /// Give several generators, separated by commas, to write and
+    /// verify the device once for each of them, in order.
+    #[clap(long, default_values_t = [GarbageGeneratorVariant::default()], value_delimiter = ',', value_parser = clap::value_parser!(GarbageGeneratorVariant))]
+    generator: Vec<GarbageGeneratorVariant>,
+
+    /// Number of threads to generate each device's data with.
+    ///
+    /// One thread is plenty for a mechanical disk, but may not keep
+    /// up with faster devices. Only the aes and blake3 generators can
+    /// use more than one thread; their output does not depend on this.
+    #[clap(long, default_value_t = NonZeroUsize::MIN)]
+    generator_threads: NonZeroUsize,
+
+    /// Write and verify the patterns that `badblocks -w` uses (0xaa,
+    /// 0x55, 0xff, 0x00), one pass each.
+    #[clap(long, conflicts_with = "generator")]
+    badblocks_patterns: bool,
+
+    /// After each pass, run another pass that writes and verifies the
+    /// bitwise complement of the same data.
+    ///
+    /// This way, every bit on the disk is shown to hold both a 0 and a 1.
+    #[clap(long)]
+    complement_pass: bool,
 
     /// Random seed to use for generating random data. By default, this tool generates its own.
+    ///
+    /// Either a 64-bit number, or a full 128- or 256-bit key given as
+    /// 32 or 64 hex digits.
+    #[clap(long)]
+    seed: Option<Seed>,
+
+    /// Give each device its own seed, derived from the seed and the
+    /// device's serial number.
+    ///
+    /// Data that ends up on a different device than it was written to
+    /// (e.g. through swapped cables) is then detected as corrupt.
+    #[clap(long)]
+    per_device_seeds: bool,
+
+    /// Stamp a small header onto every 4KiB of test data.
+    ///
+    /// The header records the offset, run and device that the data was
+    /// written for, so that the read test can tell misdirected writes,
+    /// stale data and cross-wired disks apart from plain corruption.
+    #[clap(long)]
+    stamp_blocks: bool,
+
+    /// Generate the data for the write tests only once, and write it
+    /// to all devices that use the same buffer size.
+    ///
+    /// This saves a lot of CPU time when testing many devices, but the
+    /// write test then goes only as fast as the slowest device allows.
+    #[clap(long, conflicts_with_all = ["stamp_blocks", "per_device_seeds", "start", "end"])]
+    broadcast: bool,
+
+    /// Where on the device to start testing: a number of bytes, of
+    /// 512-byte sectors (e.g. 2048s) or a percentage of the device's
+    /// capacity (e.g. 50%). Defaults to the beginning.
+    ///
+    /// Rounded down to a multiple of the buffer size.
     #[clap(long)]
-    seed: Option<u64>,
+    start: Option<Position>,
+
+    /// Where on the device to stop testing, in the same units as
+    /// --start. Defaults to the end of the device.
+    #[clap(long)]
+    end: Option<Position>,
+
+    /// How to issue reads and writes to the devices.
+    #[clap(long, value_enum, default_value_t)]
+    io_engine: IoEngine,
+
+    /// Number of reads or writes to keep in flight on each device,
+    /// with an engine that supports more than one (i.e., io-uring).
+    #[clap(long, default_value_t = NonZeroUsize::new(32).unwrap())]
+    queue_depth: NonZeroUsize,
+
+    /// Only verify that the devices hold the data that an earlier run
+    /// wrote, instead of writing and then verifying.
+    ///
+    /// Takes the run token that the earlier run logged for the pass
+    /// whose data should be on the devices (e.g. dspin1.1.4096.42.AES).
+    #[clap(long, value_name = "RUN_TOKEN", conflicts_with_all = ["generator", "badblocks_patterns", "complement_pass", "seed", "per_device_seeds", "stamp_blocks", "broadcast"])]
+    verify: Option<RunToken>,
+
+    /// Keep a checkpoint of each device's test in this directory, so
+    /// that an interrupted test can continue with --resume.
+    ///
+    /// Checkpoints are named after the devices' serial numbers, so
+    /// each device needs one.
+    #[clap(long, value_name = "DIR", conflicts_with = "broadcast")]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// Continue the tests from the checkpoints in --checkpoint-dir
+    /// instead of starting over.
+    ///
+    /// The seed is taken from the checkpoints; all other options must
+    /// be the same as for the interrupted run (give --generator
+    /// explicitly if it was "auto"). Devices without a checkpoint are
+    /// tested from the start.
+    #[clap(long, requires = "checkpoint_dir")]
+    resume: bool,
+
+    /// Keep testing a device after reading or writing it fails,
+    /// instead of stopping its test.
+    ///
+    /// Failed reads and writes are retried in ever smaller parts to
+    /// find the logical sectors that fail, which are all listed at
+    /// the end. A device with failed sectors fails the test.
+    #[clap(long)]
+    keep_going: bool,
+
+    /// Number of times to read a block again that did not read back
+    /// as written.
+    ///
+    /// Each of its sectors that did not read back as written is then
+    /// classified as persistent (wrong every time), intermittent
+    /// (wrong some of the time) or a one-off read glitch (right every
+    /// time it was read again). With 0, they all count as persistent.
+    #[clap(long, default_value_t = 3)]
+    rereads: usize,
+
+    /// Save the expected and the actual data of every block that does
+    /// not read back as written into this directory.
+    ///
+    /// The files are named <device serial>.pass<pass>.<offset>.expected
+    /// and .actual.
+    #[clap(long, value_name = "DIR")]
+    dump_dir: Option<PathBuf>,
+
+    /// Write a list of each device's bad blocks into this directory,
+    /// in the format of `badblocks -o`, for `e2fsck -l` or `mke2fs -l`.
+    ///
+    /// The lists are named <device serial>.badblocks, and hold the
+    /// blocks with sectors that could not be read or written (with
+    /// --keep-going) or that did not read back as written, except
+    /// one-off read glitches. Block numbers count from the start of
+    /// the device (or partition) that was tested.
+    #[clap(long, value_name = "DIR")]
+    bad_blocks_dir: Option<PathBuf>,
+
+    /// The size in bytes of the blocks in the bad block lists, e.g.
+    /// the block size of the filesystem that will be on the device.
+    /// Defaults to the device's logical sector size.
+    #[clap(long, value_name = "BYTES", requires = "bad_blocks_dir")]
+    bad_block_size: Option<NonZeroU64>,
 
     /// Test the device even if the media type is not a spinning disk.
     #[clap(long)]
@@ -83,6 +266,73 @@ pub(crate) struct Args {
     i_know_what_im_doing_let_me_skip_sanity_checks: bool,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Measure the throughput of each garbage generator on this machine.
+    Bench(bench::BenchArgs),
+
+    /// Check that the garbage generators produce the same output as
+    /// they did in earlier releases.
+    Selftest,
+}
+
+impl Args {
+    /// The generators to write and verify the devices with, one pass each.
+    fn passes(&self) -> Vec<GarbageGeneratorVariant> {
+        if let Some(token) = &self.verify {
+            return vec![token.generator.clone()];
+        }
+        let generators = if self.badblocks_patterns {
+            garbage::pattern::BADBLOCKS_PATTERNS
+                .iter()
+                .map(|byte| GarbageGeneratorVariant::Pattern(vec![*byte]))
+                .collect()
+        } else {
+            self.generator.clone()
+        };
+        if !self.complement_pass {
+            return generators;
+        }
+        generators
+            .into_iter()
+            .flat_map(|generator| {
+                let complement = GarbageGeneratorVariant::Complement(Box::new(generator.clone()));
+                [generator, complement]
+            })
+            .collect()
+    }
+
+    /// How to issue the I/O for the tests.
+    fn io_settings(&self) -> IoSettings {
+        IoSettings {
+            engine: self.io_engine,
+            queue_depth: self.queue_depth.get(),
+        }
+    }
+
+    /// The size of the smallest unit that `device` can transfer.
+    fn sector_size(&self, device: &ValidDevice) -> usize {
+        device
+            .device
+            .logical_block_size
+            .unwrap_or(512)
+            .try_into()
+            .unwrap()
+    }
+
+    /// The size of the buffers to read and write the device with.
+    fn buffer_size(&self, device: &ValidDevice) -> usize {
+        self.buffer_size.unwrap_or_else(|| {
+            device
+                .device
+                .physical_block_size
+                .unwrap_or(8192)
+                .try_into()
+                .unwrap()
+        })
+    }
+}
+
 fn main() -> anyhow::Result<()> {
     let indicatif_layer = IndicatifLayer::new().with_max_progress_bars(128, None);
     tracing_subscriber::registry()
@@ -90,40 +340,134 @@ fn main() -> anyhow::Result<()> {
         .with(indicatif_layer)
         .init();
     let args = Args::parse();
-    let seed = args.seed.unwrap_or_else(|| rng().random());
-    let (_, failed) = args.devices.clone().into_par_iter().map(|device| {
-        let ValidDevice {
-            device,
-            partition,
-            path,
-        } = device;
-        let buffer_size = args.buffer_size.unwrap_or_else(|| {
-            device
-                .physical_block_size
-                .unwrap_or(8192)
-                .try_into()
-                .unwrap()
-        });
-        sanity_checks(&args, partition, &path, &device)?;
-
-        info!(?seed, ?partition, ?device, ?path, "Starting test");
-        let write_generator = args.generator.to_generator(buffer_size, seed);
-        let written = write_test::write(&path, write_generator, buffer_size).context("During write test")?;
-        info!(device=?path, %written, "write test succeeded");
-        let read_generator = args.generator.to_generator(buffer_size, seed);
-        match read_test::read_back(&path, read_generator, buffer_size, written).context("During read test")? {
-            Ok(_) => {
-                info!(device=?path, "read-back test succeeded");
-                Ok(Either::Left(()))
-            }
-            Err(n) => {
-                error!(device=?path, bad_blocks=?n, "Data on disk is inconsistent/corrupted. THIS IS BAD - RMA THE DRIVE!");
-                Ok(Either::Right(path))
+    match &args.command {
+        Some(Command::Bench(bench_args)) => return bench::run(bench_args),
+        Some(Command::Selftest) => return garbage::kat::selftest(),
+        None => {}
+    }
+    let buffer_size_hint = args
+        .devices
+        .iter()
+        .map(|device| args.buffer_size(device))
+        .max()
+        .unwrap_or(8192);
+    let passes = args
+        .passes()
+        .into_iter()
+        .map(|generator| generator.resolve(buffer_size_hint, args.devices.len()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let resume = match &args.checkpoint_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Creating the checkpoint directory {dir:?}"))?;
+            let serials = args
+                .devices
+                .iter()
+                .filter_map(|device| device.device.serial_number.as_deref());
+            match args.resume {
+                true => checkpoint::load_all(dir, serials)?,
+                false => Default::default(),
             }
         }
-    }).collect::<anyhow::Result<(Vec<()>, Vec<PathBuf>)>>()?;
+        None => Default::default(),
+    };
+    if let Some(dir) = &args.dump_dir {
+        fs::create_dir_all(dir).with_context(|| format!("Creating the dump directory {dir:?}"))?;
+    }
+    if let Some(dir) = &args.bad_blocks_dir {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Creating the bad block list directory {dir:?}"))?;
+    }
+    // All checkpoints are from the same run, which the tests continue:
+    let resumed = resume.values().next();
+    if let Some(resumed) = resumed {
+        if resume
+            .values()
+            .any(|c| (c.seed, c.run_id) != (resumed.seed, resumed.run_id))
+        {
+            anyhow::bail!("The checkpoints of the devices are from different runs");
+        }
+        if args.seed.is_some_and(|seed| seed != resumed.seed) {
+            anyhow::bail!(
+                "The checkpoints were saved by a run with seed {}, not the given seed",
+                resumed.seed
+            );
+        }
+    }
+    let run = run::Run {
+        args: &args,
+        passes,
+        seed: match (&args.verify, resumed) {
+            (Some(token), _) => token.seed,
+            (None, Some(resumed)) => resumed.seed,
+            (None, None) => args.seed.unwrap_or_else(|| Seed::Short(rng().random())),
+        },
+        run_id: resumed.map_or_else(|| rng().random(), |resumed| resumed.run_id),
+        resume,
+    };
+    interrupt::install_handler()?;
+    let outcomes = if args.broadcast {
+        run.test_broadcast()?
+    } else {
+        args.devices
+            .clone()
+            .into_par_iter()
+            .map(|device| run.test_device(device, None))
+            .collect::<anyhow::Result<Vec<_>>>()?
+    };
+    let mut failed: Vec<PathBuf> = vec![];
+    let mut interrupted = vec![];
+    for outcome in outcomes {
+        match outcome {
+            run::Outcome::Passed => {}
+            run::Outcome::Failed(path) => failed.push(path),
+            run::Outcome::Interrupted(path, checkpoint) => interrupted.push((path, checkpoint)),
+        }
+    }
     if !failed.is_empty() {
         error!(devices=?failed, "Devices have failed validation. You should return them.");
+    }
+    if !interrupted.is_empty() {
+        for (path, checkpoint) in &interrupted {
+            warn!(
+                device=?path,
+                pass = checkpoint.pass,
+                phase = %checkpoint.phase,
+                bytes_done = checkpoint.offset - checkpoint.start,
+                bytes_total = checkpoint.end - checkpoint.start,
+                bad_blocks = checkpoint.bad_blocks,
+                bad_sectors = checkpoint.bad_sectors.len(),
+                "Partial result of the interrupted test"
+            );
+        }
+        match &args.checkpoint_dir {
+            Some(dir) => warn!(
+                ?dir,
+                "Saved the partial results; add --resume to continue the tests"
+            ),
+            None => {
+                // Without checkpoints to keep them in, save them next to the results that there are:
+                let dir = args.bad_blocks_dir.as_deref().unwrap_or(Path::new("."));
+                for (path, checkpoint) in &interrupted {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    let device = match checkpoint.serial.as_str() {
+                        "" => &name,
+                        serial => serial,
+                    };
+                    match checkpoint.save_result(dir, device) {
+                        Ok(saved) => {
+                            warn!(device=?path, result=?saved, "Saved the partial result; give --checkpoint-dir to be able to resume")
+                        }
+                        Err(e) => {
+                            error!(device=?path, error = %format!("{e:#}"), "Could not save the partial result")
+                        }
+                    }
+                }
+            }
+        }
+        std::process::exit(interrupt::EXIT_STATUS);
+    }
+    if !failed.is_empty() {
         anyhow::bail!("Tests not successful.");
     }
     Ok(())
@@ -0,0 +1,163 @@
+//! Describing how a block that did not read back as written differs
+//! from what was written, and saving both for a closer look later.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use tracing::warn;
+
+use crate::{file_name, rate_limit::RateLimit};
+
+/// Number of bytes around the first difference to show.
+const EXCERPT_LEN: usize = 16;
+
+/// How the data read back from a block differs from the data written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Mismatch {
+    /// Number of bytes that differ.
+    pub bytes: usize,
+    /// Number of bits that differ.
+    pub bits: u64,
+    /// Offset of the first differing byte within the block.
+    pub first: usize,
+    /// Offset within the block of the excerpts, which start at a
+    /// multiple of their length.
+    pub excerpt_at: usize,
+    pub expected: HexExcerpt,
+    pub actual: HexExcerpt,
+}
+
+impl Mismatch {
+    /// Compare the block that was read back as `have` with the data
+    /// that was written, `should`. Returns None if they are the same.
+    pub(crate) fn new(have: &[u8], should: &[u8]) -> Option<Self> {
+        let first = have.iter().zip(should).position(|(a, b)| a != b)?;
+        let (bytes, bits) = have
+            .iter()
+            .zip(should)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .filter(|bits| *bits > 0)
+            .fold((0, 0), |(bytes, total), bits| {
+                (bytes + 1, total + u64::from(bits))
+            });
+        let excerpt_at = first - first % EXCERPT_LEN;
+        let excerpt = |data: &[u8]| {
+            let end = (excerpt_at + EXCERPT_LEN).min(data.len());
+            HexExcerpt(data[excerpt_at..end].to_vec())
+        };
+        Some(Self {
+            bytes,
+            bits,
+            first,
+            excerpt_at,
+            expected: excerpt(should),
+            actual: excerpt(have),
+        })
+    }
+}
+
+/// A few bytes, formatted as space-separated hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HexExcerpt(Vec<u8>);
+
+impl fmt::Display for HexExcerpt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Saves the expected and actual data of the blocks of a pass that
+/// did not read back as written, into files named after the device,
+/// the pass and the block's offset.
+pub(crate) struct Dump {
+    dir: PathBuf,
+    prefix: String,
+    /// Limits how many of the blocks that could not be saved are logged.
+    failed: RateLimit,
+}
+
+impl Dump {
+    /// Dump the blocks of pass `pass` over the device named `device`
+    /// (its serial number, or the name of its path) into `dir`.
+    pub(crate) fn new(dir: &Path, device: &str, pass: usize) -> Self {
+        Self {
+            dir: dir.to_owned(),
+            prefix: format!("{}.pass{pass}", file_name::for_device(device)),
+            failed: RateLimit::default(),
+        }
+    }
+
+    /// Save the block at `offset` that was read back as `have`
+    /// instead of `should`.
+    pub(crate) fn save(&self, offset: u64, have: &[u8], should: &[u8]) -> anyhow::Result<()> {
+        for (kind, data) in [("expected", should), ("actual", have)] {
+            let path = self.dir.join(format!("{}.{offset}.{kind}", self.prefix));
+            fs::write(&path, data).with_context(|| format!("Dumping a bad block to {path:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::save`], but only logs failing to save the block.
+    pub(crate) fn try_save(&mut self, offset: u64, have: &[u8], should: &[u8]) {
+        if let Err(e) = self.save(offset, have, should) {
+            if let Some(unlogged) = self.failed.allow() {
+                warn!(offset, error = %format!("{e:#}"), unlogged, "Could not dump the bad block");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn describes_differences() {
+        let should = vec![0u8; 4096];
+        let mut have = should.clone();
+        have[37] = 0b1000_0001;
+        have[100] = 0xff;
+        let mismatch = Mismatch::new(&have, &should).unwrap();
+        assert_eq!(
+            (
+                mismatch.bytes,
+                mismatch.bits,
+                mismatch.first,
+                mismatch.excerpt_at
+            ),
+            (2, 10, 37, 32)
+        );
+        assert_eq!(
+            mismatch.expected.to_string(),
+            "00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00"
+        );
+        assert_eq!(
+            mismatch.actual.to_string(),
+            "00 00 00 00 00 81 00 00 00 00 00 00 00 00 00 00"
+        );
+    }
+
+    #[test]
+    fn finds_no_differences_in_equal_blocks() {
+        assert_eq!(Mismatch::new(&[1, 2, 3], &[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn dumps_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let dump = Dump::new(dir.path(), "WD-WCC/4E1", 2);
+        dump.save(8192, &[1, 2], &[1, 3]).unwrap();
+        let read = |kind| fs::read(dir.path().join(format!("WD-WCC_4E1.pass2.8192.{kind}")));
+        assert_eq!(read("expected").unwrap(), [1, 3]);
+        assert_eq!(read("actual").unwrap(), [1, 2]);
+    }
+}
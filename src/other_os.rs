@@ -3,17 +3,20 @@ use std::{
     str::FromStr,
 };
 
-use aligned_buffer::UniqueAlignedBuffer;
+use aligned_buffer::{SharedAlignedBuffer, UniqueAlignedBuffer};
 
 use crate::Args;
 
 pub const OPEN_FLAGS: i32 = libc::O_EXCL;
 
 pub type IOBuffer = UniqueAlignedBuffer<1>;
+pub type SharedIOBuffer = SharedAlignedBuffer<1>;
 
 #[derive(Debug, Clone, Default)]
 pub(crate) struct DeviceMetadata {
+    pub logical_block_size: Option<u64>,
     pub physical_block_size: Option<u64>,
+    pub serial_number: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,192 @@
+//! Generating garbage on its own thread, so that the CPU-heavy
+//! generators and the disk I/O can run at the same time.
+//!
+//! One generator thread can also feed several consumers with the same
+//! (shared, read-only) buffers, so that devices that get identical
+//! data don't each need a CPU core to generate it.
+
+use std::{
+    io::{self, Read},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    thread,
+};
+
+use anyhow::Context as _;
+
+use crate::{IOBuffer, SharedIOBuffer};
+
+/// Number of buffers that each consumer can lag behind the generator thread.
+const DEPTH: usize = 4;
+
+/// The receiving end of a generator thread: hands out buffers filled
+/// with generated data, in the order the generator produced them.
+pub(crate) struct Pipeline {
+    filled: Receiver<io::Result<SharedIOBuffer>>,
+    empty: SyncSender<IOBuffer>,
+}
+
+impl Pipeline {
+    /// Returns the next buffer of generated data.
+    pub(crate) fn next(&mut self) -> anyhow::Result<SharedIOBuffer> {
+        self.filled
+            .recv()
+            .context("Generator thread exited unexpectedly")?
+            .context("Generating pseudorandom data")
+    }
+
+    /// Hand a buffer back to the generator thread, to be filled again
+    /// once no other consumer uses it anymore.
+    pub(crate) fn recycle(&mut self, buf: SharedIOBuffer) {
+        if let Ok(buf) = SharedIOBuffer::try_unique(buf) {
+            // If the generator thread is gone or has enough empty
+            // buffers already, this one can just be dropped.
+            let _ = self.empty.try_send(buf);
+        }
+    }
+}
+
+/// Run `generator` on a separate thread, filling buffers of
+/// `buffer_size` bytes that `f` can then retrieve from the [`Pipeline`].
+///
+/// The generator thread stops once `f` returns.
+pub(crate) fn with_generator<R>(
+    generator: impl Read + Send,
+    buffer_size: usize,
+    f: impl FnOnce(&mut Pipeline) -> R,
+) -> R {
+    thread::scope(|s| {
+        let mut pipeline = broadcast(s, generator, buffer_size, 1)
+            .pop()
+            .expect("There is one pipeline per consumer");
+        let result = f(&mut pipeline);
+        // Hang up, so that the generator thread exits:
+        drop(pipeline);
+        result
+    })
+}
+
+/// Start a thread in `scope` that runs `generator`, sending each buffer
+/// it fills to all of the returned `consumers` pipelines.
+///
+/// The generator thread only ever runs [`DEPTH`] buffers ahead of the
+/// slowest consumer, and stops when all pipelines are dropped.
+pub(crate) fn broadcast<'scope>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    generator: impl Read + Send + 'scope,
+    buffer_size: usize,
+    consumers: usize,
+) -> Vec<Pipeline> {
+    let (empty_tx, empty_rx) = sync_channel(DEPTH);
+    for _ in 0..DEPTH {
+        empty_tx
+            .send(new_buffer(buffer_size))
+            .expect("The channel has room for all buffers");
+    }
+    let (senders, pipelines) = (0..consumers)
+        .map(|_| {
+            let (filled_tx, filled_rx) = sync_channel(DEPTH);
+            let pipeline = Pipeline {
+                filled: filled_rx,
+                empty: empty_tx.clone(),
+            };
+            (filled_tx, pipeline)
+        })
+        .unzip();
+    scope.spawn(move || fill(generator, buffer_size, empty_rx, senders));
+    pipelines
+}
+
+fn new_buffer(buffer_size: usize) -> IOBuffer {
+    let mut buf = IOBuffer::with_capacity(buffer_size);
+    buf.resize(buffer_size, 0);
+    buf
+}
+
+fn fill(
+    mut generator: impl Read,
+    buffer_size: usize,
+    empty: Receiver<IOBuffer>,
+    mut consumers: Vec<SyncSender<io::Result<SharedIOBuffer>>>,
+) {
+    while !consumers.is_empty() {
+        // If all buffers are still in use by a slow consumer, allocate
+        // another; the channels' capacity bounds how many there can be.
+        let mut buf = empty.try_recv().unwrap_or_else(|_| new_buffer(buffer_size));
+        if let Err(e) = generator.read_exact(&mut buf) {
+            for consumer in consumers {
+                let _ = consumer.send(Err(io::Error::new(e.kind(), e.to_string())));
+            }
+            return;
+        }
+        let buf = buf.into_shared();
+        // Consumers that hung up are done, and don't need any more data:
+        consumers.retain(|consumer| consumer.send(Ok(buf.clone())).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{broadcast, with_generator};
+    use std::{io, thread};
+
+    #[test]
+    fn delivers_in_order() {
+        let input: Vec<u8> = (0..=255).cycle().take(64 * 1024).collect();
+        let output = with_generator(io::Cursor::new(input.clone()), 1024, |buffers| {
+            let mut output = vec![];
+            for _ in 0..64 {
+                let buf = buffers.next().unwrap();
+                output.extend_from_slice(&buf);
+                buffers.recycle(buf);
+            }
+            output
+        });
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn reports_generator_errors() {
+        with_generator(io::Cursor::new(vec![0; 1536]), 1024, |buffers| {
+            let buf = buffers.next().unwrap();
+            buffers.recycle(buf);
+            assert!(buffers.next().is_err());
+        });
+    }
+
+    #[test]
+    fn stops_when_done() {
+        // An infinite generator must not keep the pipeline alive:
+        with_generator(io::repeat(1), 1024, |buffers| {
+            assert_eq!(buffers.next().unwrap()[0], 1);
+        });
+    }
+
+    #[test]
+    fn broadcasts_to_all_consumers() {
+        let input: Vec<u8> = (0..=255).cycle().take(64 * 1024).collect();
+        thread::scope(|s| {
+            let pipelines = broadcast(s, io::Cursor::new(input.clone()), 1024, 3);
+            let consumers: Vec<_> = pipelines
+                .into_iter()
+                .enumerate()
+                .map(|(i, mut buffers)| {
+                    // The first consumer stops early; that must not stop the others.
+                    let blocks = if i == 0 { 3 } else { 64 };
+                    s.spawn(move || {
+                        let mut output = vec![];
+                        for _ in 0..blocks {
+                            let buf = buffers.next().unwrap();
+                            output.extend_from_slice(&buf);
+                            buffers.recycle(buf);
+                        }
+                        output
+                    })
+                })
+                .collect();
+            for (i, consumer) in consumers.into_iter().enumerate() {
+                let output = consumer.join().unwrap();
+                assert_eq!(output, input[..output.len()], "consumer {i}");
+            }
+        });
+    }
+}
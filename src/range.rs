@@ -0,0 +1,178 @@
+//! Testing only a part of a device.
+
+use std::{fmt, str::FromStr};
+
+/// Size of the sectors that positions can be given in. This is what
+/// Linux counts sectors in, regardless of the device's block size.
+const SECTOR: u64 = 512;
+
+/// A position on the device: a number of bytes (e.g. `1048576`), of
+/// 512-byte sectors (e.g. `2048s`) or a percentage of the device's
+/// capacity (e.g. `50%` or `12.5%`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Position {
+    Bytes(u64),
+    Sectors(u64),
+    Percent(f64),
+}
+
+impl Position {
+    /// The byte offset of this position on a device of `capacity` bytes.
+    fn resolve(&self, capacity: u64) -> u64 {
+        let offset = match self {
+            Position::Bytes(bytes) => *bytes,
+            Position::Sectors(sectors) => sectors.saturating_mul(SECTOR),
+            Position::Percent(percent) => (capacity as f64 * percent / 100.0) as u64,
+        };
+        offset.min(capacity)
+    }
+}
+
+impl FromStr for Position {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Invalid position {s:?}; expected a number of bytes, of sectors (like 2048s) or a percentage (like 50%)"
+            )
+        };
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent: f64 = percent.parse().map_err(|_| invalid())?;
+            if !(0.0..=100.0).contains(&percent) {
+                anyhow::bail!("Percentage {s:?} must be between 0% and 100%");
+            }
+            return Ok(Position::Percent(percent));
+        }
+        if let Some(sectors) = s.strip_suffix('s') {
+            return sectors
+                .parse()
+                .map(Position::Sectors)
+                .map_err(|_| invalid());
+        }
+        s.parse().map(Position::Bytes).map_err(|_| invalid())
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Position::Bytes(bytes) => write!(f, "{bytes}"),
+            Position::Sectors(sectors) => write!(f, "{sectors}s"),
+            Position::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+/// The bytes of a device that a test covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+    /// The capacity of the whole device.
+    pub capacity: u64,
+}
+
+impl ByteRange {
+    /// The range between `start` (or the beginning) and `end` (or the
+    /// end) on a device of `capacity` bytes, with both ends rounded
+    /// down to a multiple of `buffer_size`.
+    pub(crate) fn resolve(
+        start: Option<Position>,
+        end: Option<Position>,
+        capacity: u64,
+        buffer_size: usize,
+    ) -> anyhow::Result<Self> {
+        let align = |offset: u64| offset - offset % buffer_size as u64;
+        let range = Self {
+            start: align(start.map_or(0, |start| start.resolve(capacity))),
+            end: align(end.map_or(capacity, |end| end.resolve(capacity))),
+            capacity,
+        };
+        if range.start >= range.end {
+            anyhow::bail!(
+                "The range to test ({range}) does not hold a single {buffer_size}-byte buffer"
+            );
+        }
+        Ok(range)
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Whether the range covers the device (up to the last full buffer).
+    pub(crate) fn is_whole_device(&self, buffer_size: usize) -> bool {
+        self.start == 0 && self.capacity - self.end < buffer_size as u64
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |offset: u64| offset as f64 * 100.0 / self.capacity.max(1) as f64;
+        write!(
+            f,
+            "bytes {}..{} of {} ({:.2}%..{:.2}%)",
+            self.start,
+            self.end,
+            self.capacity,
+            percent(self.start),
+            percent(self.end)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("1048576", Position::Bytes(1048576); "bytes")]
+    #[test_case("2048s", Position::Sectors(2048); "sectors")]
+    #[test_case("12.5%", Position::Percent(12.5); "percent")]
+    fn parses_positions(s: &str, expected: Position) {
+        assert_eq!(s.parse::<Position>().unwrap(), expected);
+        assert_eq!(expected.to_string(), s);
+    }
+
+    #[test_case("101%"; "over 100 percent")]
+    #[test_case("-1"; "negative")]
+    #[test_case("1k"; "unknown unit")]
+    #[test_case(""; "empty")]
+    fn rejects_invalid_positions(s: &str) {
+        assert!(s.parse::<Position>().is_err());
+    }
+
+    #[test_case(None, None, 0, 999_424; "whole device")]
+    #[test_case(Some("2s"), Some("50%"), 0, 499_712; "aligned down")]
+    #[test_case(Some("8192"), Some("2000000"), 8192, 999_424; "end beyond capacity")]
+    fn resolves_ranges(
+        start: Option<&str>,
+        end: Option<&str>,
+        expected_start: u64,
+        expected_end: u64,
+    ) {
+        let range = ByteRange::resolve(
+            start.map(|s| s.parse().unwrap()),
+            end.map(|s| s.parse().unwrap()),
+            1_000_000,
+            4096,
+        )
+        .unwrap();
+        assert_eq!((range.start, range.end), (expected_start, expected_end));
+    }
+
+    #[test]
+    fn rejects_empty_ranges() {
+        let range = |start: &str, end: &str| {
+            ByteRange::resolve(
+                Some(start.parse().unwrap()),
+                Some(end.parse().unwrap()),
+                1_000_000,
+                4096,
+            )
+        };
+        assert!(range("50%", "40%").is_err());
+        assert!(range("4096", "8000").is_err());
+    }
+}
@@ -0,0 +1,48 @@
+//! Limiting how often a kind of message is logged, so that a dying
+//! device doesn't drown the log in one line per block.
+
+use std::time::{Duration, Instant};
+
+/// Shortest time between two messages of a kind.
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Decides which messages of a kind to log: one per [`LOG_INTERVAL`].
+#[derive(Debug, Default)]
+pub(crate) struct RateLimit {
+    last_logged: Option<Instant>,
+    /// Number of messages not logged since the last one that was.
+    pub(crate) unlogged: usize,
+}
+
+impl RateLimit {
+    /// Returns whether to log a message now, and if so, how many were
+    /// not logged since the last one that was.
+    pub(crate) fn allow(&mut self) -> Option<usize> {
+        let now = Instant::now();
+        if self
+            .last_logged
+            .is_some_and(|logged| now.duration_since(logged) < LOG_INTERVAL)
+        {
+            self.unlogged += 1;
+            return None;
+        }
+        self.last_logged = Some(now);
+        Some(std::mem::take(&mut self.unlogged))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn logs_one_message_per_interval() {
+        let mut limit = RateLimit::default();
+        assert_eq!(limit.allow(), Some(0));
+        assert_eq!(limit.allow(), None);
+        assert_eq!(limit.allow(), None);
+        // As if the interval passed:
+        limit.last_logged = Instant::now().checked_sub(LOG_INTERVAL);
+        assert_eq!(limit.allow(), Some(2));
+    }
+}
@@ -1,33 +1,133 @@
 //! Running the "read back" portion of the test.
 
-use crate::{garbage::GarbageGenerator, IOBuffer, OPEN_FLAGS, PROGRESS_STYLE};
-use anyhow::Context;
-use std::{
-    fs::OpenOptions,
-    io::{BufReader, Read},
-    os::unix::fs::OpenOptionsExt as _,
-    path::Path,
+use crate::{
+    bad_sectors::BadSectors,
+    checkpoint::Checkpointer,
+    classify::Classifier,
+    engine::{self, Engine, IoSettings},
+    extents::BadExtents,
+    garbage::{stamp::Stamp, GarbageGenerator},
+    interrupt,
+    mismatch::{Dump, Mismatch},
+    pipeline,
+    rate_limit::RateLimit,
+    recheck::{Persistence, Recheck, Verdict},
+    IOBuffer, PROGRESS_STYLE,
 };
-use tracing::{info_span, warn};
+use anyhow::Context;
+use std::{io::Read, path::Path};
+use tracing::{error, info_span, warn};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
 type FailedReads = usize;
 
-#[tracing::instrument(skip(generator))]
+/// What the read test does with a block that does not read back as
+/// written, besides counting it.
+#[derive(Default)]
+pub(crate) struct Inspect<'a> {
+    /// Explains mismatches with the headers in the data, if it was
+    /// stamped.
+    pub stamp: Option<&'a Stamp>,
+    /// Reads the block again and classifies its bad sectors.
+    pub recheck: Option<Recheck<'a>>,
+    /// Classifies what the block holds instead of what was written.
+    pub classifier: Option<Classifier<'a>>,
+    /// Saves what the block holds and what was written.
+    pub dump: Option<Dump>,
+    /// Merges the bad blocks into extents and limits how many are
+    /// logged; without it, every bad block is logged.
+    pub extents: Option<&'a mut BadExtents>,
+}
+
+impl Inspect<'_> {
+    /// Look into the block at `offset`, which was read back as `have`
+    /// instead of `should`. Returns whether it counts as a bad block,
+    /// which it doesn't if the host turns out to be at fault.
+    fn bad_block(
+        &mut self,
+        blockdev: &mut dyn Engine<IOBuffer>,
+        offset: u64,
+        have: &[u8],
+        should: &[u8],
+        mismatch: Mismatch,
+    ) -> anyhow::Result<bool> {
+        let verdict = match self.recheck.as_mut() {
+            Some(recheck) => Some(recheck.examine(blockdev, offset, have, should)?),
+            None => None,
+        };
+        if verdict == Some(Verdict::HostSide) {
+            error!(offset, "The device holds the data that was written, but it was compared with wrongly generated data. The host may be faulty (e.g. its memory)!");
+            return Ok(false);
+        }
+        let count = |persistence| match &verdict {
+            Some(Verdict::Sectors(sectors)) => {
+                Some(sectors.iter().filter(|(_, p)| *p == persistence).count())
+            }
+            _ => None,
+        };
+        let corruption = match self.classifier.as_mut() {
+            Some(classifier) => Some(classifier.classify(offset, have, &mismatch)?),
+            None => None,
+        };
+        let logged = match self.extents.as_deref_mut() {
+            Some(extents) => extents.record(offset, have.len() as u64, corruption),
+            None => Some(0),
+        };
+        if let Some(unlogged) = logged {
+            let diagnosis = self.stamp.map(|stamp| stamp.diagnose(offset, have));
+            warn!(
+                offset,
+                corruption = corruption.map(tracing::field::display),
+                differing_bytes = mismatch.bytes,
+                differing_bits = mismatch.bits,
+                first_difference = mismatch.first,
+                excerpt_at = mismatch.excerpt_at,
+                expected = %mismatch.expected,
+                actual = %mismatch.actual,
+                diagnosis = diagnosis.as_ref().map(tracing::field::display),
+                persistent = count(Persistence::Persistent),
+                intermittent = count(Persistence::Intermittent),
+                one_off = count(Persistence::OneOff),
+                unlogged_extents = unlogged,
+                "Did not read back the exact bytes written"
+            );
+        }
+        if let Some(dump) = &mut self.dump {
+            dump.try_save(offset, have, should);
+        }
+        Ok(true)
+    }
+}
+
+/// Which bytes of a device the read test verifies, and what it does
+/// with what it finds.
+#[derive(Default)]
+pub(crate) struct Verify<'a> {
+    /// Offset of the first byte to verify.
+    pub start: u64,
+    /// Number of bytes to verify, which were written from `start` on.
+    pub written: usize,
+    /// Keeps track of how far the test got.
+    pub checkpoint: Option<&'a mut Checkpointer>,
+    /// If given, reads that fail don't end the test; the sectors that
+    /// they fail on are recorded there instead.
+    pub bad_sectors: Option<&'a mut BadSectors>,
+    pub inspect: Inspect<'a>,
+}
+
+/// Verify the bytes that `verify` covers, which the generator produces
+/// starting at its current position.
+#[tracing::instrument(skip(generator, verify), fields(start = verify.start, written = verify.written))]
 pub(crate) fn read_back(
     dev_path: &Path,
     generator: Box<dyn GarbageGenerator>,
     buffer_size: usize,
-    written: usize,
+    io: IoSettings,
+    verify: Verify,
 ) -> anyhow::Result<Result<(), FailedReads>> {
-    let blockdev = OpenOptions::new()
-        .read(true)
-        .custom_flags(OPEN_FLAGS)
-        .open(dev_path)
-        .with_context(|| format!("Opening the device {dev_path:?} for reading"))?;
-
-    let generator = BufReader::new(generator);
-    let mismatched = compare_persisted_bytes(blockdev, generator, buffer_size, written)?;
+    let blockdev = engine::open(dev_path, io)?;
+
+    let mismatched = compare_persisted_bytes(blockdev, generator, buffer_size, verify)?;
     if mismatched > 0 {
         return Ok(Err(mismatched));
     }
@@ -35,45 +135,94 @@ pub(crate) fn read_back(
 }
 
 fn compare_persisted_bytes(
-    mut blockdev: impl Read,
-    mut generator: impl Read,
+    mut blockdev: Box<dyn Engine<IOBuffer>>,
+    generator: impl Read + Send,
     buffer_size: usize,
-    written: usize,
+    verify: Verify,
 ) -> anyhow::Result<usize> {
+    let Verify {
+        start,
+        written,
+        mut checkpoint,
+        mut bad_sectors,
+        mut inspect,
+    } = verify;
     let bar_span = info_span!("reading back");
     bar_span.pb_set_style(&PROGRESS_STYLE);
     bar_span.pb_set_length(written as u64);
     let _bar_span_handle = bar_span.enter();
-    let mut mismatches = 0;
-    let mut offset = 0;
-    let mut should = IOBuffer::with_capacity(buffer_size);
-    should.resize(buffer_size, 0);
-    let mut have = IOBuffer::with_capacity(buffer_size);
-    have.resize(buffer_size, 0);
-    while offset < written {
-        generator
-            .read_exact(&mut should)
-            .context("Generating pseudorandom data")?;
-        match blockdev.read_exact(&mut have) {
-            Ok(_) => {}
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+    let mut read_failed = RateLimit::default();
+    let (mismatches, validated) = pipeline::with_generator(generator, buffer_size, |buffers| {
+        let mut mismatches = 0;
+        let mut validated = 0;
+        let mut submitted = 0;
+        let mut free: Vec<IOBuffer> = vec![];
+        loop {
+            while submitted < written && !interrupt::requested() && blockdev.has_room() {
+                let have = free.pop().unwrap_or_else(|| {
+                    let mut have = IOBuffer::with_capacity(buffer_size);
+                    have.resize(buffer_size, 0);
+                    have
+                });
+                blockdev
+                    .submit(start + submitted as u64, have)
+                    .with_context(|| {
+                        format!("Submitting a read at offset={}", start + submitted as u64)
+                    })?;
+                submitted += buffer_size;
+            }
+            let Some(done) = blockdev.complete().context("Waiting for a read")? else {
                 break;
+            };
+            let should = buffers.next()?;
+            let (offset, mut have) = (done.offset, done.buf);
+            match done.result {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    break;
+                }
+                Err(e) => match bad_sectors.as_deref_mut() {
+                    Some(bad_sectors) => {
+                        let failed = bad_sectors
+                            .bisect(&mut *blockdev, offset, &mut have, &e)
+                            .with_context(|| format!("Reading bytes on disk at offset={offset}"))?;
+                        if let Some(unlogged) = read_failed.allow() {
+                            warn!(offset, error = %e, failed_sectors = failed.len(), unlogged, "Read failed; skipping the failed sectors");
+                        }
+                        // Failed sectors are reported as such, so only
+                        // the others count as mismatches:
+                        for failed in failed {
+                            let sector = failed..failed + bad_sectors.sector_size;
+                            have[sector.clone()].copy_from_slice(&should[sector]);
+                        }
+                    }
+                    None => anyhow::bail!("Reading bytes on disk at offset={}: {:?}", offset, e),
+                },
+            }
+            if let Some(mismatch) = Mismatch::new(&have, &should) {
+                if inspect.bad_block(&mut *blockdev, offset, &have, &should, mismatch)? {
+                    mismatches += 1;
+                }
+            }
+            buffers.recycle(should);
+            free.push(have);
+            validated += buffer_size;
+            bar_span.pb_inc(buffer_size as u64);
+            if let Some(checkpoint) = checkpoint.as_deref_mut() {
+                // Reading leaves nothing to flush:
+                checkpoint.progress(
+                    offset + buffer_size as u64,
+                    mismatches,
+                    bad_sectors.as_deref(),
+                    || Ok(()),
+                )?;
             }
-            error => error.map_err(|e| anyhow::anyhow!("Reading bytes on disk: {:?}", e))?,
-        }
-        if have.is_empty() {
-            break;
-        }
-        if *have != *should {
-            warn!(offset, "Did not read back the exact bytes written");
-            mismatches += 1;
         }
-        offset += buffer_size;
-        bar_span.pb_inc(buffer_size as u64);
-    }
-    if offset != written {
+        anyhow::Ok((mismatches, validated))
+    })?;
+    if validated != written && !interrupt::requested() {
         warn!(
-            validated = offset,
+            validated,
             written, "Number of bytes validated and written is not the same."
         );
     }
@@ -82,20 +231,93 @@ fn compare_persisted_bytes(
 
 #[cfg(test)]
 mod test {
-    use super::compare_persisted_bytes;
-    use std::io;
+    use super::{compare_persisted_bytes, Verify};
+    use crate::{
+        bad_sectors::BadSectors,
+        engine::{self, Done, Engine, IoEngine, IoSettings},
+        IOBuffer,
+    };
+    use std::{
+        io::{self, Write as _},
+        ops::Range,
+    };
     use tracing_test::traced_test;
 
+    const IO: IoSettings = IoSettings {
+        engine: IoEngine::Blocking,
+        queue_depth: 1,
+    };
+
+    /// A file holding `data`, to read back from.
+    fn device(data: &[u8]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(data).unwrap();
+        file
+    }
+
+    /// Fails to read anything that touches one of its 512-byte sectors.
+    struct BadSector(Box<dyn Engine<IOBuffer>>, &'static [u64]);
+
+    impl BadSector {
+        fn touches(&self, start: u64, end: u64) -> bool {
+            (start / 512..end.div_ceil(512)).any(|sector| self.1.contains(&sector))
+        }
+    }
+
+    impl Engine<IOBuffer> for BadSector {
+        fn has_room(&self) -> bool {
+            self.0.has_room()
+        }
+
+        fn submit(&mut self, offset: u64, buf: IOBuffer) -> io::Result<()> {
+            self.0.submit(offset, buf)
+        }
+
+        fn complete(&mut self) -> io::Result<Option<Done<IOBuffer>>> {
+            let done = self.0.complete()?;
+            Ok(done.map(|mut done| {
+                if self.touches(done.offset, done.offset + done.buf.len() as u64) {
+                    done.result = Err(io::Error::from_raw_os_error(libc::EIO));
+                }
+                done
+            }))
+        }
+
+        fn transfer_part(
+            &mut self,
+            offset: u64,
+            buf: &mut IOBuffer,
+            part: Range<usize>,
+        ) -> io::Result<()> {
+            if self.touches(offset + part.start as u64, offset + part.end as u64) {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            self.0.transfer_part(offset, buf, part)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.0.sync()
+        }
+    }
+
     #[traced_test]
     #[test]
     fn detects_issues() {
         let input: Vec<u8> = vec![1; 1024 * 1024];
         let mut read_back: Vec<u8> = vec![1; 1024 * 1024];
         read_back[1024 * 512] = 255; // corrupt our read-back data
-        let read_back = io::Cursor::new(read_back);
+        let read_back = device(&read_back);
 
-        let mismatched =
-            compare_persisted_bytes(read_back, io::Cursor::new(input), 1024, 1024 * 1024).unwrap();
+        let mismatched = compare_persisted_bytes(
+            engine::open(read_back.path(), IO).unwrap(),
+            io::Cursor::new(input),
+            4096,
+            Verify {
+                written: 1024 * 1024,
+                ..Verify::default()
+            },
+        )
+        .unwrap();
         assert_eq!(mismatched, 1);
     }
 
@@ -103,10 +325,45 @@ mod test {
     #[test]
     fn succeeds() {
         let input: Vec<u8> = vec![1; 1024 * 1024];
-        let read_back: Vec<u8> = vec![1; 1024 * 1024];
-        let read_back = io::Cursor::new(read_back);
-        let mismatched =
-            compare_persisted_bytes(read_back, io::Cursor::new(input), 1024, 1024 * 1024).unwrap();
+        let read_back = device(&vec![1; 1024 * 1024]);
+        let mismatched = compare_persisted_bytes(
+            engine::open(read_back.path(), IO).unwrap(),
+            io::Cursor::new(input),
+            4096,
+            Verify {
+                written: 1024 * 1024,
+                ..Verify::default()
+            },
+        )
+        .unwrap();
         assert_eq!(mismatched, 0);
     }
+
+    #[traced_test]
+    #[test]
+    fn compares_the_readable_sectors_of_failed_reads() {
+        let input: Vec<u8> = vec![1; 64 * 1024];
+        let mut read_back: Vec<u8> = vec![1; 64 * 1024];
+        // The sector after the bad one in the same block is corrupt:
+        read_back[8192 + 512] = 255;
+        let read_back = device(&read_back);
+
+        let mut bad_sectors = BadSectors::new(512);
+        let mismatched = compare_persisted_bytes(
+            Box::new(BadSector(
+                engine::open(read_back.path(), IO).unwrap(),
+                &[16, 40],
+            )),
+            io::Cursor::new(input),
+            4096,
+            Verify {
+                written: 64 * 1024,
+                bad_sectors: Some(&mut bad_sectors),
+                ..Verify::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(mismatched, 1);
+        assert_eq!(bad_sectors.to_string(), "16,40");
+    }
 }
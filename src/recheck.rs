@@ -0,0 +1,264 @@
+//! A closer look at blocks that did not read back as written.
+//!
+//! Generating a block's data again rules out a problem on the host
+//! (e.g. flaky memory), and reading it again a few times tells sectors
+//! that hold the wrong data apart from those that were just read wrong
+//! once.
+
+use std::{collections::BTreeMap, fmt, io::Read as _};
+
+use anyhow::Context as _;
+use tracing::warn;
+
+use crate::{engine::Engine, garbage::GarbageGenerator, rate_limit::RateLimit, IOBuffer};
+
+/// How a sector that did not read back as written behaves when it is
+/// read again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Persistence {
+    /// Read back right every time it was read again: a read glitch.
+    OneOff,
+    /// Read back wrong some of the times it was read again.
+    Intermittent,
+    /// Read back wrong every time.
+    Persistent,
+}
+
+impl fmt::Display for Persistence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Persistence::OneOff => "one-off",
+            Persistence::Intermittent => "intermittent",
+            Persistence::Persistent => "persistent",
+        })
+    }
+}
+
+/// The sectors of a device that did not read back as written, over
+/// all passes.
+#[derive(Debug, Clone)]
+pub(crate) struct SuspectSectors {
+    pub sector_size: usize,
+    /// The worst behavior of each sector, by byte offset.
+    sectors: BTreeMap<u64, Persistence>,
+}
+
+impl SuspectSectors {
+    pub(crate) fn new(sector_size: usize) -> Self {
+        Self {
+            sector_size,
+            sectors: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sectors.is_empty()
+    }
+
+    /// The byte offset and worst behavior of each sector, in order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, Persistence)> + '_ {
+        self.sectors.iter().map(|(offset, p)| (*offset, *p))
+    }
+
+    /// Number of sectors that behaved like `persistence`.
+    pub(crate) fn count(&self, persistence: Persistence) -> usize {
+        self.sectors.values().filter(|p| **p == persistence).count()
+    }
+
+    fn record(&mut self, offset: u64, persistence: Persistence) {
+        let worst = self.sectors.entry(offset).or_insert(persistence);
+        *worst = persistence.max(*worst);
+    }
+}
+
+impl fmt::Display for SuspectSectors {
+    /// Lists each sector's number and behavior, like "1234:persistent".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (offset, persistence)) in self.sectors.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}:{persistence}", offset / self.sector_size as u64)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a closer look at a block that did not read back as written
+/// found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// The device holds the right data, but the data it was compared
+    /// with was generated wrong.
+    HostSide,
+    /// The block's sectors that did not read back as written, by byte
+    /// offset.
+    Sectors(Vec<(u64, Persistence)>),
+}
+
+/// Takes a closer look at the blocks of a pass that did not read back
+/// as written.
+pub(crate) struct Recheck<'a> {
+    /// Generates the data that the pass wrote.
+    generator: Box<dyn GarbageGenerator>,
+    /// How often each block is read again.
+    rereads: usize,
+    suspects: &'a mut SuspectSectors,
+    expected: Vec<u8>,
+    reread: IOBuffer,
+    /// Limit how many of the warnings about blocks are logged.
+    regenerated_differently: RateLimit,
+    reread_failed: RateLimit,
+}
+
+impl<'a> Recheck<'a> {
+    pub(crate) fn new(
+        generator: Box<dyn GarbageGenerator>,
+        rereads: usize,
+        suspects: &'a mut SuspectSectors,
+    ) -> Self {
+        Self {
+            generator,
+            rereads,
+            suspects,
+            expected: vec![],
+            reread: IOBuffer::with_capacity(0),
+            regenerated_differently: RateLimit::default(),
+            reread_failed: RateLimit::default(),
+        }
+    }
+
+    /// Take a closer look at the block at `offset`, which read back as
+    /// `have` instead of `should`.
+    pub(crate) fn examine(
+        &mut self,
+        engine: &mut dyn Engine<IOBuffer>,
+        offset: u64,
+        have: &[u8],
+        should: &[u8],
+    ) -> anyhow::Result<Verdict> {
+        self.expected.resize(should.len(), 0);
+        self.generator
+            .seek_to(offset)
+            .and_then(|_| self.generator.read_exact(&mut self.expected))
+            .context("Generating the expected data again")?;
+        if self.expected != should {
+            if self.expected == have {
+                return Ok(Verdict::HostSide);
+            }
+            if let Some(unlogged) = self.regenerated_differently.allow() {
+                warn!(
+                    offset,
+                    unlogged,
+                    "Generating the expected data again gave different data; the host may be faulty"
+                );
+            }
+        }
+
+        let sector_size = self.suspects.sector_size;
+        let differs = |data: &[u8], expected: &[u8]| -> Vec<bool> {
+            data.chunks(sector_size)
+                .zip(expected.chunks(sector_size))
+                .map(|(data, expected)| data != expected)
+                .collect()
+        };
+        let first = differs(have, &self.expected);
+        let mut wrong_rereads = vec![0; first.len()];
+        let mut rereads = 0;
+        if self.reread.len() != have.len() {
+            self.reread = IOBuffer::with_capacity(have.len());
+            self.reread.resize(have.len(), 0);
+        }
+        for _ in 0..self.rereads {
+            if let Err(e) = engine.transfer_part(offset, &mut self.reread, 0..have.len()) {
+                if let Some(unlogged) = self.reread_failed.allow() {
+                    warn!(offset, error = %e, unlogged, "Reading a block again failed");
+                }
+                continue;
+            }
+            rereads += 1;
+            for (wrong, differs) in wrong_rereads
+                .iter_mut()
+                .zip(differs(&self.reread, &self.expected))
+            {
+                *wrong += differs as usize;
+            }
+        }
+
+        let mut sectors = vec![];
+        for (i, (first, wrong)) in first.into_iter().zip(wrong_rereads).enumerate() {
+            let persistence = match (first, wrong) {
+                (false, 0) => continue,
+                (true, 0) if rereads > 0 => Persistence::OneOff,
+                (true, wrong) if wrong == rereads => Persistence::Persistent,
+                _ => Persistence::Intermittent,
+            };
+            let sector = offset + (i * sector_size) as u64;
+            self.suspects.record(sector, persistence);
+            sectors.push((sector, persistence));
+        }
+        Ok(Verdict::Sectors(sectors))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::{self, IoEngine, IoSettings};
+    use std::io::{self, Write as _};
+
+    /// A generator that repeats the same byte, wherever it is.
+    struct Repeat(u8);
+
+    impl io::Read for Repeat {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            buf.fill(self.0);
+            Ok(buf.len())
+        }
+    }
+
+    impl GarbageGenerator for Repeat {
+        fn seek_to(&mut self, _offset: u64) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn examine(on_disk: &[u8], have: &[u8], should: &[u8]) -> (Verdict, SuspectSectors) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(on_disk).unwrap();
+        let io = IoSettings {
+            engine: IoEngine::Blocking,
+            queue_depth: 1,
+        };
+        let mut engine = engine::open(file.path(), io).unwrap();
+        let mut suspects = SuspectSectors::new(512);
+        let verdict = Recheck::new(Box::new(Repeat(7)), 3, &mut suspects)
+            .examine(&mut *engine, 0, have, should)
+            .unwrap();
+        (verdict, suspects)
+    }
+
+    #[test]
+    fn classifies_sectors() {
+        let mut on_disk = vec![7; 4096];
+        on_disk[512] = 0; // Wrong on disk, so every time
+        let mut have = on_disk.clone();
+        have[1024] = 0; // Wrong in the first read only
+        let (verdict, suspects) = examine(&on_disk, &have, &[7; 4096]);
+        assert_eq!(
+            verdict,
+            Verdict::Sectors(vec![
+                (512, Persistence::Persistent),
+                (1024, Persistence::OneOff)
+            ])
+        );
+        assert_eq!(suspects.to_string(), "1:persistent,2:one-off");
+    }
+
+    #[test]
+    fn blames_the_host_for_wrongly_generated_data() {
+        let (verdict, suspects) = examine(&[7; 4096], &[7; 4096], &[6; 4096]);
+        assert_eq!(verdict, Verdict::HostSide);
+        assert!(suspects.is_empty());
+    }
+}
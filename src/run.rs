@@ -0,0 +1,485 @@
+//! Running the tests on all the devices.
+
+use std::{
+    collections::BTreeMap,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::Context as _;
+use tracing::{error, info, warn};
+
+use crate::{
+    bad_block_list::BadBlockList,
+    bad_sectors::BadSectors,
+    checkpoint::{Checkpoint, Checkpointer, Phase},
+    classify::{Classifier, CorruptionCounts},
+    extents::{BadExtents, Class},
+    garbage::{
+        derive_device_seed, stamp::Stamp, token::RunToken, GarbageGenerator,
+        GarbageGeneratorVariant, Seed,
+    },
+    interrupt,
+    mismatch::Dump,
+    pipeline::{self, Pipeline},
+    range::ByteRange,
+    read_test::{self, Inspect, Verify},
+    recheck::{Persistence, Recheck, SuspectSectors},
+    sanity_checks, write_test, Args, ValidDevice,
+};
+
+/// Number of bad extents to list at most at the end of a device's test.
+const MAX_LISTED_EXTENTS: usize = 1000;
+
+/// Settings that are shared by the tests of all devices.
+pub(crate) struct Run<'a> {
+    pub args: &'a Args,
+    /// The generators to write and verify each device with, one pass each.
+    pub passes: Vec<GarbageGeneratorVariant>,
+    pub seed: Seed,
+    pub run_id: u64,
+    /// The checkpoints to resume the tests from, by device serial.
+    pub resume: BTreeMap<String, Checkpoint>,
+}
+
+/// The result of testing a device.
+pub(crate) enum Outcome {
+    Passed,
+    Failed(PathBuf),
+    /// The test was interrupted, after getting as far as the checkpoint.
+    Interrupted(PathBuf, Box<Checkpoint>),
+}
+
+impl Run<'_> {
+    /// Run all passes on a device.
+    ///
+    /// If `shared_writes` is given, it holds one pipeline per pass
+    /// with the data to write in that pass.
+    pub(crate) fn test_device(
+        &self,
+        device: ValidDevice,
+        shared_writes: Option<Vec<Pipeline>>,
+    ) -> anyhow::Result<Outcome> {
+        let args = self.args;
+        let buffer_size = args.buffer_size(&device);
+        let sector_size = args.sector_size(&device);
+        let ValidDevice {
+            device,
+            partition,
+            path,
+        } = device;
+        let mut shared_writes = shared_writes.map(Vec::into_iter);
+        sanity_checks(args, partition, &path, &device)?;
+        let seed = self.device_seed(&path, device.serial_number.as_deref())?;
+
+        let capacity = crate::determine_size(&path)?;
+        let range = ByteRange::resolve(args.start, args.end, capacity, buffer_size)?;
+        info!(%seed, ?partition, ?device, ?path, %range, "Starting test");
+        if !range.is_whole_device(buffer_size) {
+            warn!(device=?path, %range, "Testing only part of the device");
+        }
+        let resume = device
+            .serial_number
+            .as_deref()
+            .and_then(|serial| self.resume.get(serial));
+        if let Some(checkpoint) = resume {
+            self.check_resumable(checkpoint, &path, seed, buffer_size, range)?;
+            info!(device=?path, pass = checkpoint.pass, phase = %checkpoint.phase, offset = checkpoint.offset, bad_blocks = checkpoint.bad_blocks, "Resuming from checkpoint");
+        }
+        let serial = match (&args.checkpoint_dir, &device.serial_number) {
+            (_, Some(serial)) => serial.clone(),
+            (None, None) => String::new(),
+            (Some(_), None) => {
+                anyhow::bail!("Device {path:?} has no serial number to name its checkpoint after")
+            }
+        };
+        // Tracks how far the test got, even without a checkpoint directory:
+        let mut checkpointer = Checkpointer::new(
+            args.checkpoint_dir.as_deref(),
+            resume.cloned().unwrap_or_else(|| Checkpoint {
+                serial,
+                run_id: self.run_id,
+                seed: self.seed,
+                buffer_size,
+                start: range.start,
+                end: range.end,
+                pass: 0,
+                token: RunToken {
+                    generator: self.passes[0].clone(),
+                    seed,
+                },
+                phase: Phase::Write,
+                offset: range.start,
+                bad_blocks: 0,
+                bad_sectors: vec![],
+            }),
+        );
+
+        let mut bad_sectors = args.keep_going.then(|| {
+            let resumed = resume.map_or(&[][..], |checkpoint| &checkpoint.bad_sectors);
+            BadSectors::from_sectors(sector_size, resumed.iter().copied())
+        });
+        let mut suspects = SuspectSectors::new(sector_size);
+        let mut corruption = CorruptionCounts::default();
+        let mut extents = BadExtents::default();
+        let mut bad_blocks = resume.map_or(0, |checkpoint| checkpoint.bad_blocks);
+        let (first_pass, mut resume_at) = match resume {
+            Some(checkpoint) if checkpoint.phase == Phase::Done => (self.passes.len(), None),
+            Some(checkpoint) => (checkpoint.pass, Some((checkpoint.phase, checkpoint.offset))),
+            None => (0, None),
+        };
+        // An error that stopped the test, returned only after reporting
+        // what the test found until then:
+        let mut failure = None;
+        for (pass, generator) in self.passes.iter().enumerate().skip(first_pass) {
+            if interrupt::requested() {
+                return Ok(Outcome::Interrupted(
+                    path,
+                    Box::new(checkpointer.interrupt()?),
+                ));
+            }
+            let (phase, offset) = resume_at.take().unwrap_or(match args.verify {
+                Some(_) => (Phase::Verify, range.start),
+                None => (Phase::Write, range.start),
+            });
+            let serial = device.serial_number.as_deref();
+            let stamp = self.stamp(pass, serial);
+            let to_generator = |offset: u64, threads: usize| {
+                self.pass_generator(pass, seed, serial, offset, threads)
+            };
+
+            let token = RunToken {
+                generator: generator.clone(),
+                seed,
+            };
+            // Stamped data depends on more than the token, so it can't verify it:
+            let verifiable_token = stamp.is_none().then_some(&token);
+            info!(device=?path, pass, %generator, generator_version = generator.version()?, %seed, token = verifiable_token.map(tracing::field::display), ?stamp, "Starting pass");
+            let written = if phase == Phase::Verify {
+                // Whatever a write test would have written:
+                range.len() as usize
+            } else {
+                checkpointer.start(pass, &token, Phase::Write, offset, bad_blocks)?;
+                let remaining = ByteRange {
+                    start: offset,
+                    ..range
+                };
+                let written = match shared_writes.as_mut().and_then(Iterator::next) {
+                    Some(mut buffers) => write_test::write(
+                        &path,
+                        &mut buffers,
+                        buffer_size,
+                        args.io_settings(),
+                        remaining,
+                        Some(&mut checkpointer),
+                        bad_sectors.as_mut(),
+                    ),
+                    None => {
+                        let generator = to_generator(offset, args.generator_threads.get())?;
+                        pipeline::with_generator(generator, buffer_size, |buffers| {
+                            write_test::write(
+                                &path,
+                                buffers,
+                                buffer_size,
+                                args.io_settings(),
+                                remaining,
+                                Some(&mut checkpointer),
+                                bad_sectors.as_mut(),
+                            )
+                        })
+                    }
+                };
+                let written = match written.context("During write test") {
+                    Ok(written) => written,
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
+                    }
+                };
+                if interrupt::requested() {
+                    return Ok(Outcome::Interrupted(
+                        path,
+                        Box::new(checkpointer.interrupt()?),
+                    ));
+                }
+                info!(device=?path, pass, %written, %range, "write test succeeded");
+                (offset - range.start) as usize + written
+            };
+            let verify_from = match phase {
+                Phase::Verify => offset,
+                _ => range.start,
+            };
+            checkpointer.start(pass, &token, Phase::Verify, verify_from, bad_blocks)?;
+            // Generating single blocks again is not worth extra threads:
+            let earlier_passes = (0..pass)
+                .map(|earlier| self.pass_generator(earlier, seed, serial, 0, 1))
+                .collect::<anyhow::Result<_>>()?;
+            extents.start_pass(pass);
+            let inspect = Inspect {
+                stamp: stamp.as_ref(),
+                recheck: Some(Recheck::new(
+                    to_generator(0, 1)?,
+                    args.rereads,
+                    &mut suspects,
+                )),
+                classifier: Some(Classifier::new(
+                    to_generator(0, 1)?,
+                    earlier_passes,
+                    stamp.clone(),
+                    &mut corruption,
+                )),
+                dump: args.dump_dir.as_deref().map(|dir| {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    Dump::new(dir, serial.unwrap_or(&name), pass)
+                }),
+                extents: Some(&mut extents),
+            };
+            match read_test::read_back(
+                &path,
+                to_generator(verify_from, args.generator_threads.get())?,
+                buffer_size,
+                args.io_settings(),
+                Verify {
+                    start: verify_from,
+                    written: written.saturating_sub((verify_from - range.start) as usize),
+                    checkpoint: Some(&mut checkpointer),
+                    bad_sectors: bad_sectors.as_mut(),
+                    inspect,
+                },
+            )
+            .context("During read test")
+            {
+                Ok(Ok(_)) => info!(device=?path, pass, %range, "read-back test succeeded"),
+                Ok(Err(n)) => {
+                    error!(device=?path, pass, %generator, generator_version = generator.version()?, %seed, token = verifiable_token.map(tracing::field::display), %range, bad_blocks=?n, "Read-back test found inconsistent data");
+                    bad_blocks += n;
+                }
+                Err(e) => {
+                    bad_blocks = checkpointer.bad_blocks();
+                    failure = Some(e);
+                    break;
+                }
+            }
+            if interrupt::requested() {
+                return Ok(Outcome::Interrupted(
+                    path,
+                    Box::new(checkpointer.interrupt()?),
+                ));
+            }
+        }
+        if failure.is_none() {
+            checkpointer.finish(bad_blocks)?;
+        }
+        if let Some(dir) = &args.bad_blocks_dir {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let device_name = device.serial_number.as_deref().unwrap_or(&name);
+            self.save_bad_block_list(dir, device_name, &path, bad_sectors.as_ref(), &suspects)?;
+        }
+        let bad_sectors = bad_sectors.filter(|bad_sectors| !bad_sectors.is_empty());
+        if let Some(bad_sectors) = &bad_sectors {
+            error!(device=?path, sector_size, count = bad_sectors.len(), sectors = %bad_sectors, "Sectors could not be read or written. THIS IS BAD - RMA THE DRIVE!");
+        }
+        if !suspects.is_empty() {
+            error!(
+                device=?path,
+                sector_size,
+                persistent = suspects.count(Persistence::Persistent),
+                intermittent = suspects.count(Persistence::Intermittent),
+                one_off = suspects.count(Persistence::OneOff),
+                sectors = %suspects,
+                "Sectors did not read back as written"
+            );
+        }
+        if bad_blocks > 0 {
+            error!(device=?path, %seed, generator_version = ?self.generator_versions()?, bad_blocks, %corruption, extents = extents.len(), "Data on disk is inconsistent/corrupted. THIS IS BAD - RMA THE DRIVE!");
+            for extent in extents.iter().take(MAX_LISTED_EXTENTS) {
+                error!(device=?path, pass = extent.pass, start = extent.start, end = extent.end(), bytes = extent.len, class = %Class(extent.class), "Bad extent");
+            }
+            if extents.len() > MAX_LISTED_EXTENTS {
+                error!(device=?path, not_listed = extents.len() - MAX_LISTED_EXTENTS, "Too many bad extents to list them all");
+            }
+        }
+        if let Some(checkpoint) = resume.filter(|_| bad_blocks > 0 || !suspects.is_empty()) {
+            warn!(device=?path, pass = checkpoint.pass, phase = %checkpoint.phase, offset = checkpoint.offset, "The test was resumed from a checkpoint; the corruption classes, the bad extents and the sectors that did not read back as written (also in the bad block list) only cover what was read since then");
+        }
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        if bad_blocks > 0 || bad_sectors.is_some() {
+            return Ok(Outcome::Failed(path));
+        }
+        Ok(Outcome::Passed)
+    }
+
+    /// Write the list of the blocks of the device at `path` that hold
+    /// `bad_sectors` or `suspects` into `dir`.
+    fn save_bad_block_list(
+        &self,
+        dir: &Path,
+        device_name: &str,
+        path: &Path,
+        bad_sectors: Option<&BadSectors>,
+        suspects: &SuspectSectors,
+    ) -> anyhow::Result<()> {
+        let sector_size = suspects.sector_size as u64;
+        let block_size = self
+            .args
+            .bad_block_size
+            .map_or(sector_size, NonZeroU64::get);
+        let mut list = BadBlockList::new(block_size);
+        for sector in bad_sectors.into_iter().flat_map(BadSectors::sectors) {
+            list.add(sector * sector_size, sector_size);
+        }
+        for (offset, persistence) in suspects.iter() {
+            if persistence != Persistence::OneOff {
+                list.add(offset, sector_size);
+            }
+        }
+        let saved = list.save(dir, device_name)?;
+        info!(device=?path, block_size, blocks = list.len(), list = ?saved, "Wrote the bad block list");
+        Ok(())
+    }
+
+    /// Make sure that `checkpoint` was saved by a test with the same
+    /// settings as this one, so that resuming from it is sound.
+    fn check_resumable(
+        &self,
+        checkpoint: &Checkpoint,
+        path: &Path,
+        seed: Seed,
+        buffer_size: usize,
+        range: ByteRange,
+    ) -> anyhow::Result<()> {
+        let differs = |what: &str| {
+            anyhow::anyhow!(
+                "The checkpoint of device {path:?} was saved with a different {what}; give the same options as the interrupted run, or start over without --resume"
+            )
+        };
+        if checkpoint.buffer_size != buffer_size {
+            return Err(differs("buffer size"));
+        }
+        if (checkpoint.start, checkpoint.end) != (range.start, range.end) {
+            return Err(differs("range to test"));
+        }
+        let token = self.passes.get(checkpoint.pass).map(|generator| RunToken {
+            generator: generator.clone(),
+            seed,
+        });
+        if token.as_ref() != Some(&checkpoint.token) {
+            return Err(differs("generator or seed"));
+        }
+        if !checkpoint.bad_sectors.is_empty() && !self.args.keep_going {
+            return Err(differs("--keep-going setting"));
+        }
+        Ok(())
+    }
+
+    /// The stamp for the data that pass `pass` writes to the device
+    /// with `serial`, if the data is stamped.
+    fn stamp(&self, pass: usize, serial: Option<&str>) -> Option<Stamp> {
+        self.args
+            .stamp_blocks
+            .then(|| Stamp::new(self.run_id, pass as u32, serial))
+    }
+
+    /// A generator for the data that pass `pass` writes to the device
+    /// with `serial`, starting at `offset`.
+    fn pass_generator(
+        &self,
+        pass: usize,
+        seed: Seed,
+        serial: Option<&str>,
+        offset: u64,
+        threads: usize,
+    ) -> anyhow::Result<Box<dyn GarbageGenerator>> {
+        let generator = self.passes[pass].to_parallel_generator(seed, threads)?;
+        let mut generator = match self.stamp(pass, serial) {
+            Some(stamp) => stamp.wrap(generator),
+            None => generator,
+        };
+        // The data for each offset is the same, no matter where the test starts:
+        generator
+            .seek_to(offset)
+            .context("Seeking the generator to where the test starts")?;
+        Ok(generator)
+    }
+
+    /// The versions of the generators of all passes, in order; they
+    /// matter as much as the seed for generating the data again.
+    fn generator_versions(&self) -> anyhow::Result<Vec<u32>> {
+        self.passes
+            .iter()
+            .map(GarbageGeneratorVariant::version)
+            .collect()
+    }
+
+    /// The seed for the generators of the device at `path`.
+    fn device_seed(&self, path: &Path, serial: Option<&str>) -> anyhow::Result<Seed> {
+        if !self.args.per_device_seeds {
+            return Ok(self.seed);
+        }
+        let serial = serial.with_context(|| {
+            format!("Device {path:?} has no serial number to derive a per-device seed from")
+        })?;
+        let seed = derive_device_seed(self.seed, serial);
+        info!(device=?path, serial, master_seed = %self.seed, %seed, generator_version = ?self.generator_versions()?, "Derived per-device seed");
+        Ok(seed)
+    }
+
+    /// Test all devices at the same time, with one generator per pass
+    /// and buffer size supplying the data for the write tests of all
+    /// devices with that buffer size.
+    pub(crate) fn test_broadcast(&self) -> anyhow::Result<Vec<Outcome>> {
+        let mut groups: BTreeMap<usize, Vec<ValidDevice>> = BTreeMap::new();
+        for device in &self.args.devices {
+            groups
+                .entry(self.args.buffer_size(device))
+                .or_default()
+                .push(device.clone());
+        }
+        // Start all generators before any test, so that one that fails
+        // to start can't leave the other devices' tests half-done.
+        let groups = groups
+            .into_iter()
+            .map(|(buffer_size, devices)| {
+                let generators = self
+                    .passes
+                    .iter()
+                    .map(|generator| {
+                        generator
+                            .to_parallel_generator(self.seed, self.args.generator_threads.get())
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Ok((buffer_size, devices, generators))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        thread::scope(|s| {
+            // Every device needs its own thread here, as the generators
+            // can only run as far ahead as the slowest device lets them.
+            let mut workers = vec![];
+            for (buffer_size, devices, generators) in groups {
+                info!(
+                    buffer_size,
+                    devices = devices.len(),
+                    "Sharing generated data"
+                );
+                let mut per_pass: Vec<_> = generators
+                    .into_iter()
+                    .map(|generator| pipeline::broadcast(s, generator, buffer_size, devices.len()))
+                    .collect();
+                for device in devices {
+                    let shared_writes = per_pass
+                        .iter_mut()
+                        .map(|pipelines| pipelines.pop().expect("One pipeline per device"))
+                        .collect();
+                    workers.push(s.spawn(move || self.test_device(device, Some(shared_writes))));
+                }
+            }
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("Device test thread panicked"))
+                .collect()
+        })
+    }
+}
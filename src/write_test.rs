@@ -1,58 +1,158 @@
 //! Running the "write" portion of the test.
 
-use crate::{garbage::GarbageGenerator, IOBuffer, OPEN_FLAGS, PROGRESS_STYLE};
+use crate::{
+    bad_sectors::BadSectors,
+    checkpoint::Checkpointer,
+    engine::{self, Engine, IoSettings},
+    interrupt,
+    pipeline::Pipeline,
+    range::ByteRange,
+    rate_limit::RateLimit,
+    SharedIOBuffer, PROGRESS_STYLE,
+};
 use anyhow::Context;
-use std::os::unix::fs::OpenOptionsExt as _;
-use std::{fs::OpenOptions, io, path::Path};
-use tracing::info_span;
+use std::{io, path::Path};
+use tracing::{info_span, warn};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
-#[tracing::instrument(skip(generator))]
+/// Write the data from `buffers` to the `range` of the device.
+///
+/// If `bad_sectors` is given, writes that fail don't end the test;
+/// the sectors that they fail on are recorded there instead.
+#[tracing::instrument(skip(buffers, checkpoint, bad_sectors))]
 pub(crate) fn write(
     dev_path: &Path,
-    generator: Box<dyn GarbageGenerator>,
+    buffers: &mut Pipeline,
     buffer_size: usize,
+    io: IoSettings,
+    range: ByteRange,
+    checkpoint: Option<&mut Checkpointer>,
+    bad_sectors: Option<&mut BadSectors>,
 ) -> anyhow::Result<usize> {
-    let capacity = crate::determine_size(dev_path)?;
-    let out = OpenOptions::new()
-        .write(true)
-        .custom_flags(OPEN_FLAGS)
-        .open(dev_path)
-        .with_context(|| format!("Opening the device {dev_path:?} for writing"))?;
-    write_garbage(out, generator, capacity, buffer_size)
+    let out = engine::open(dev_path, io)?;
+    write_garbage(out, buffers, range, buffer_size, checkpoint, bad_sectors)
 }
 
 fn write_garbage(
-    mut blockdev: impl io::Write,
-    mut generator: impl io::Read,
-    capacity: u64,
+    mut blockdev: Box<dyn Engine<SharedIOBuffer>>,
+    buffers: &mut Pipeline,
+    range: ByteRange,
     buffer_size: usize,
+    mut checkpoint: Option<&mut Checkpointer>,
+    mut bad_sectors: Option<&mut BadSectors>,
 ) -> anyhow::Result<usize> {
     let bar_span = info_span!("writing");
     bar_span.pb_set_style(&PROGRESS_STYLE);
-    bar_span.pb_set_length(capacity);
+    bar_span.pb_set_length(range.len());
     let _bar_span_handle = bar_span.enter();
 
-    let mut buf = IOBuffer::with_capacity(buffer_size);
-    buf.resize(buffer_size, 0);
-    let mut done = 0;
+    let mut write_failed = RateLimit::default();
+    let mut submitted = range.start;
+    // The offset at which the disk turned out to be full:
+    let mut full_at: Option<u64> = None;
     loop {
-        generator
-            .read_exact(&mut buf)
-            .context("Generating pseudorandom data")?;
-        match blockdev.write_all(&buf) {
+        // When interrupted, stop submitting and let the writes in flight finish:
+        while full_at.is_none()
+            && !interrupt::requested()
+            && submitted < range.end
+            && blockdev.has_room()
+        {
+            let buf = buffers.next()?;
+            blockdev
+                .submit(submitted, buf)
+                .with_context(|| format!("Submitting a write at offset={submitted}"))?;
+            submitted += buffer_size as u64;
+        }
+        let Some(done) = blockdev.complete().context("Waiting for a write")? else {
+            break;
+        };
+        let mut buf = done.buf;
+        match done.result {
             Ok(_) => {}
-            Err(e) if e.raw_os_error() == Some(28) => {
-                // "disk full", meaning we're done:
-                return Ok(done);
-            }
-            Err(e) if e.kind() == io::ErrorKind::WriteZero => {
-                // "disk full" on macOS, meaning we're done:
-                return Ok(done);
+            Err(e) if e.raw_os_error() == Some(28) || e.kind() == io::ErrorKind::WriteZero => {
+                // "disk full" (on Linux or on macOS), meaning we're done
+                // (once the writes that are still in flight finish):
+                full_at = Some(full_at.map_or(done.offset, |at| at.min(done.offset)));
+                continue;
             }
-            Err(e) => anyhow::bail!("io Error at offset={done:?} {:?}: kind {:?}", e, e.kind()),
+            Err(e) => match bad_sectors.as_deref_mut() {
+                Some(bad_sectors) => {
+                    let failed = bad_sectors
+                        .bisect(&mut *blockdev, done.offset, &mut buf, &e)
+                        .with_context(|| format!("Writing at offset={}", done.offset))?;
+                    if let Some(unlogged) = write_failed.allow() {
+                        warn!(offset = done.offset, error = %e, failed_sectors = failed.len(), unlogged, "Write failed; skipping the failed sectors");
+                    }
+                }
+                None => anyhow::bail!(
+                    "io Error at offset={:?} {:?}: kind {:?}",
+                    done.offset,
+                    e,
+                    e.kind()
+                ),
+            },
         };
-        done += buffer_size;
+        buffers.recycle(buf);
         bar_span.pb_inc(buffer_size as u64);
+        if let Some(checkpoint) = checkpoint.as_deref_mut() {
+            // A checkpoint must not claim writes that a power loss could undo:
+            checkpoint.progress(
+                done.offset + buffer_size as u64,
+                0,
+                bad_sectors.as_deref(),
+                || {
+                    blockdev
+                        .sync()
+                        .context("Flushing the written data to the device")
+                },
+            )?;
+        }
+    }
+    blockdev
+        .sync()
+        .context("Flushing the written data to the device")?;
+    Ok((full_at.unwrap_or(submitted) - range.start) as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_garbage;
+    use crate::{
+        engine::{self, IoEngine, IoSettings},
+        pipeline,
+        range::ByteRange,
+    };
+    use std::io::{self, Write as _};
+
+    #[test]
+    fn writes_only_the_range() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[0; 16 * 4096]).unwrap();
+        let io = IoSettings {
+            engine: IoEngine::Blocking,
+            queue_depth: 1,
+        };
+        let range = ByteRange {
+            start: 2 * 4096,
+            end: 6 * 4096,
+            capacity: 16 * 4096,
+        };
+        let written = pipeline::with_generator(io::repeat(7), 4096, |buffers| {
+            write_garbage(
+                engine::open(file.path(), io).unwrap(),
+                buffers,
+                range,
+                4096,
+                None,
+                None,
+            )
+        })
+        .unwrap();
+        assert_eq!(written, 4 * 4096);
+
+        let contents = std::fs::read(file.path()).unwrap();
+        assert!(contents[..2 * 4096].iter().all(|b| *b == 0));
+        assert!(contents[2 * 4096..6 * 4096].iter().all(|b| *b == 7));
+        assert!(contents[6 * 4096..].iter().all(|b| *b == 0));
     }
 }